@@ -0,0 +1,93 @@
+use glam::Vec3A;
+use std::f32;
+
+use ray::Ray;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: Vec3A,
+    pub max: Vec3A,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3A, max: Vec3A) -> Aabb {
+        Aabb { min, max }
+    }
+
+    pub fn empty() -> Aabb {
+        Aabb::new(Vec3A::splat(f32::INFINITY), Vec3A::splat(f32::NEG_INFINITY))
+    }
+
+    /// Bounds for things that extend forever, such as planes
+    pub fn infinite() -> Aabb {
+        Aabb::new(Vec3A::splat(f32::NEG_INFINITY), Vec3A::splat(f32::INFINITY))
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.min.min_element().is_finite() && self.max.max_element().is_finite()
+    }
+
+    /// Grow by `margin` on every side, to give flat bounds some thickness
+    pub fn pad(&self, margin: f32) -> Aabb {
+        Aabb::new(self.min - Vec3A::splat(margin), self.max + Vec3A::splat(margin))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x() > self.max.x() || self.min.y() > self.max.y() || self.min.z() > self.max.z()
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::new(self.min.min(other.min), self.max.max(other.max))
+    }
+
+    pub fn grow(&self, p: Vec3A) -> Aabb {
+        Aabb::new(self.min.min(p), self.max.max(p))
+    }
+
+    pub fn offset(&self, offset: Vec3A) -> Aabb {
+        Aabb::new(self.min + offset, self.max + offset)
+    }
+
+    pub fn centroid(&self) -> Vec3A {
+        0.5 * (self.min + self.max)
+    }
+
+    pub fn extent(&self) -> Vec3A {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let d = self.extent();
+        2.0 * (d.x() * d.y() + d.y() * d.z() + d.z() * d.x())
+    }
+
+    pub fn largest_axis(&self) -> usize {
+        let d = self.extent();
+        if d.x() > d.y() && d.x() > d.z() {
+            0
+        } else if d.y() > d.z() {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Slab test. `inv_direction` is the component-wise reciprocal of the ray direction,
+    /// computed once per ray by the caller.
+    pub fn hit(&self, r: &Ray, inv_direction: Vec3A, t_min: f32, t_max: f32) -> bool {
+        let t0 = (self.min - r.origin) * inv_direction;
+        let t1 = (self.max - r.origin) * inv_direction;
+        let t_near = t0.min(t1).max_element().max(t_min);
+        let t_far = t0.max(t1).min_element().min(t_max);
+        t_near <= t_far
+    }
+}
+
+impl Default for Aabb {
+    fn default() -> Aabb {
+        Aabb::empty()
+    }
+}
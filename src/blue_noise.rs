@@ -0,0 +1,179 @@
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+
+const SIGMA: f32 = 1.5;
+const INITIAL_DENSITY: f32 = 0.1;
+// The R2 low discrepancy sequence's additive constants, 1/g and 1/g^2 for the plastic number g,
+// in 0.32 fixed point
+const R2: (u32, u32) = (3_242_174_889, 2_447_445_414);
+
+/// A tileable blue noise mask. Every texel holds a distinct rank, and the texels of any range of
+/// consecutive ranks are spread evenly over the tile, so thresholding it at any level gives a
+/// blue noise point set. Generated using Ulichney's void-and-cluster method.
+#[derive(Debug)]
+pub struct BlueNoise {
+    size: usize,
+    ranks: Vec<u32>,
+}
+
+// Gaussian energy of every texel due to a set of points on the torus
+struct Energy {
+    size: usize,
+    kernel: Vec<f32>,
+    values: Vec<f32>,
+}
+
+impl Energy {
+    fn new(size: usize) -> Energy {
+        let mut kernel = vec![0.0; size * size];
+        for y in 0..size {
+            for x in 0..size {
+                let dx = x.min(size - x) as f32;
+                let dy = y.min(size - y) as f32;
+                kernel[y * size + x] = (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp();
+            }
+        }
+        Energy { size, kernel, values: vec![0.0; size * size] }
+    }
+
+    fn from_points(size: usize, points: &[bool]) -> Energy {
+        let mut energy = Energy::new(size);
+        for (i, _) in points.iter().enumerate().filter(|(_, &p)| p) {
+            energy.update(i, 1.0);
+        }
+        energy
+    }
+
+    fn update(&mut self, point: usize, sign: f32) {
+        let size = self.size;
+        let (px, py) = (point % size, point / size);
+        for y in 0..size {
+            let ky = (y + size - py) % size;
+            for x in 0..size {
+                let kx = (x + size - px) % size;
+                self.values[y * size + x] += sign * self.kernel[ky * size + kx];
+            }
+        }
+    }
+
+    // The point in the densest cluster
+    fn tightest_cluster(&self, points: &[bool]) -> usize {
+        self.extreme(points, true, |a, b| a > b)
+    }
+
+    // The empty texel furthest from any point
+    fn largest_void(&self, points: &[bool]) -> usize {
+        self.extreme(points, false, |a, b| a < b)
+    }
+
+    fn extreme<F>(&self, points: &[bool], of: bool, better: F) -> usize where F: Fn(f32, f32) -> bool {
+        let mut best = None;
+        for (i, (&p, &e)) in points.iter().zip(self.values.iter()).enumerate() {
+            if p != of {
+                continue;
+            }
+            let is_better = match best {
+                None => true,
+                Some((_, best_e)) => better(e, best_e),
+            };
+            if is_better {
+                best = Some((i, e));
+            }
+        }
+        best.unwrap().0
+    }
+}
+
+impl BlueNoise {
+    /// Generate a `size` by `size` tile. The result is fully determined by `seed`.
+    pub fn generate(size: usize, seed: u64) -> BlueNoise {
+        let n = size * size;
+        let mut rng = SmallRng::seed_from_u64(seed);
+
+        // Start from white noise and move points from the tightest cluster to the largest void
+        // until that no longer changes anything
+        let mut initial = vec![false; n];
+        let initial_count = ((n as f32 * INITIAL_DENSITY) as usize).max(1);
+        let mut placed = 0;
+        while placed < initial_count {
+            let i = rng.gen_range(0, n);
+            if !initial[i] {
+                initial[i] = true;
+                placed += 1;
+            }
+        }
+        let mut energy = Energy::from_points(size, &initial);
+        loop {
+            let cluster = energy.tightest_cluster(&initial);
+            initial[cluster] = false;
+            energy.update(cluster, -1.0);
+            let void = energy.largest_void(&initial);
+            initial[void] = true;
+            energy.update(void, 1.0);
+            if void == cluster {
+                break;
+            }
+        }
+
+        let mut ranks = vec![0u32; n];
+
+        // Rank the initial points by repeatedly removing the tightest cluster
+        let mut points = initial.clone();
+        let mut energy = Energy::from_points(size, &points);
+        for rank in (0..initial_count).rev() {
+            let cluster = energy.tightest_cluster(&points);
+            points[cluster] = false;
+            energy.update(cluster, -1.0);
+            ranks[cluster] = rank as u32;
+        }
+
+        // Fill the largest voids up to half of the texels
+        let mut points = initial;
+        let mut energy = Energy::from_points(size, &points);
+        for rank in initial_count..n / 2 {
+            let void = energy.largest_void(&points);
+            points[void] = true;
+            energy.update(void, 1.0);
+            ranks[void] = rank as u32;
+        }
+
+        // Beyond half the empty texels are the minority, so fill the tightest clusters of them
+        let empty: Vec<bool> = points.iter().map(|&p| !p).collect();
+        let mut energy = Energy::from_points(size, &empty);
+        let mut empty = empty;
+        for rank in n / 2..n {
+            let cluster = energy.tightest_cluster(&empty);
+            empty[cluster] = false;
+            energy.update(cluster, -1.0);
+            ranks[cluster] = rank as u32;
+        }
+
+        BlueNoise { size, ranks }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The rank of a texel, wrapping coordinates around the tile
+    pub fn rank(&self, x: usize, y: usize) -> u32 {
+        self.ranks[(y % self.size) * self.size + x % self.size]
+    }
+
+    /// The rank of a texel mapped to [0, 1)
+    pub fn value(&self, x: usize, y: usize) -> f32 {
+        (self.rank(x, y) as f32 + 0.5) / self.ranks.len() as f32
+    }
+
+    /// A point in [0, 1)^2 for sample `index` of pixel (x, y). Successive samples of a pixel follow
+    /// the R2 sequence, offset per pixel by the noise so that each sample index is itself a blue
+    /// noise pattern over the image.
+    pub fn sample_2d(&self, x: usize, y: usize, index: u32) -> (f32, f32) {
+        let half = self.size / 2;
+        let offset = |value: f32, step: u32| {
+            let value = (value * 4_294_967_296.0) as u32;
+            (value.wrapping_add(index.wrapping_mul(step)) >> 8) as f32 / 16_777_216.0
+        };
+        (offset(self.value(x, y), R2.0), offset(self.value(x + half, y + half), R2.1))
+    }
+}
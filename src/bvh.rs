@@ -0,0 +1,357 @@
+use glam::Vec3A;
+
+use aabb::Aabb;
+use ray::Ray;
+
+const BIN_COUNT: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+// Below this depth only median splits are made so that the traversal stack cannot overflow
+const MAX_SAH_DEPTH: usize = 40;
+const STACK_SIZE: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+struct BvhNode {
+    bounds: Aabb,
+    // Leaf: index of the first item. Interior: index of the second child, the first
+    // child immediately follows its parent.
+    offset: u32,
+    // Number of items in a leaf, 0 for interior nodes
+    count: u32,
+    axis: u8,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Bin {
+    bounds: Aabb,
+    count: usize,
+}
+
+/// A bounding volume hierarchy over arbitrary items, built using a binned surface area heuristic.
+/// Items with infinite bounds, such as planes, are kept outside of the tree and always visited.
+#[derive(Debug)]
+pub struct Bvh<T> {
+    nodes: Vec<BvhNode>,
+    items: Vec<T>,
+    unbounded: Vec<T>,
+}
+
+impl<T> Default for Bvh<T> {
+    fn default() -> Bvh<T> {
+        Bvh { nodes: Vec::new(), items: Vec::new(), unbounded: Vec::new() }
+    }
+}
+
+impl<T> Bvh<T> {
+    pub fn build(primitives: Vec<(Aabb, T)>) -> Bvh<T> {
+        let mut bounds = Vec::with_capacity(primitives.len());
+        let mut centroids = Vec::with_capacity(primitives.len());
+        let mut items = Vec::with_capacity(primitives.len());
+        let mut unbounded = Vec::new();
+        for (aabb, item) in primitives {
+            if !aabb.is_empty() && !aabb.is_finite() {
+                unbounded.push(item);
+                continue;
+            }
+            bounds.push(aabb);
+            centroids.push(aabb.centroid());
+            items.push(Some(item));
+        }
+        let mut order: Vec<usize> = (0..items.len()).collect();
+        let mut nodes = Vec::with_capacity(2 * items.len());
+        if !items.is_empty() {
+            build_recursive(&bounds, &centroids, &mut order, 0, 0, &mut nodes);
+        }
+        Bvh {
+            nodes,
+            items: order.iter().map(|&i| items[i].take().unwrap()).collect(),
+            unbounded,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.unbounded.is_empty()
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        if !self.unbounded.is_empty() {
+            return Aabb::infinite();
+        }
+        self.nodes.first().map_or(Aabb::empty(), |node| node.bounds)
+    }
+
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// Recompute node bounds bottom-up without changing the tree topology. This is much cheaper
+    /// than a rebuild when items have moved a little, at the cost of tree quality.
+    pub fn refit<F>(&mut self, bounds: F) where F: Fn(&T) -> Aabb {
+        for i in (0..self.nodes.len()).rev() {
+            let node = self.nodes[i];
+            let offset = node.offset as usize;
+            self.nodes[i].bounds = if node.count > 0 {
+                self.items[offset..offset + node.count as usize]
+                    .iter()
+                    .fold(Aabb::empty(), |acc, item| acc.union(&bounds(item)))
+            } else {
+                self.nodes[i + 1].bounds.union(&self.nodes[offset].bounds)
+            };
+        }
+    }
+
+    /// Visit the items whose bounds are hit by the ray, front to back where possible. `hit` is
+    /// given the current `t_max` and returns the distance of any closer intersection it finds,
+    /// which is then used to cull the rest of the traversal. Returns the closest distance found.
+    pub fn traverse<F>(&self, r: &Ray, t_min: f32, mut t_max: f32, mut hit: F) -> Option<f32>
+        where F: FnMut(&T, f32) -> Option<f32>
+    {
+        let mut closest = None;
+        for item in &self.unbounded {
+            if let Some(t) = hit(item, t_max) {
+                t_max = t;
+                closest = Some(t);
+            }
+        }
+        if self.nodes.is_empty() {
+            return closest;
+        }
+        let inv_direction = Vec3A::one() / r.direction;
+        let direction_is_negative = [
+            r.direction.x() < 0.0,
+            r.direction.y() < 0.0,
+            r.direction.z() < 0.0,
+        ];
+        let mut stack = [0usize; STACK_SIZE];
+        let mut stack_size = 0;
+        let mut node_index = 0;
+        loop {
+            let node = &self.nodes[node_index];
+            if node.bounds.hit(r, inv_direction, t_min, t_max) {
+                let offset = node.offset as usize;
+                if node.count > 0 {
+                    for item in &self.items[offset..offset + node.count as usize] {
+                        if let Some(t) = hit(item, t_max) {
+                            t_max = t;
+                            closest = Some(t);
+                        }
+                    }
+                } else if direction_is_negative[node.axis as usize] {
+                    stack[stack_size] = node_index + 1;
+                    stack_size += 1;
+                    node_index = offset;
+                    continue;
+                } else {
+                    stack[stack_size] = offset;
+                    stack_size += 1;
+                    node_index += 1;
+                    continue;
+                }
+            }
+            if stack_size == 0 {
+                break;
+            }
+            stack_size -= 1;
+            node_index = stack[stack_size];
+        }
+        closest
+    }
+}
+
+fn build_recursive(
+    bounds: &[Aabb],
+    centroids: &[Vec3A],
+    order: &mut [usize],
+    offset: usize,
+    depth: usize,
+    nodes: &mut Vec<BvhNode>,
+) -> usize {
+    let node_index = nodes.len();
+    let mut node_bounds = Aabb::empty();
+    let mut centroid_bounds = Aabb::empty();
+    for &i in order.iter() {
+        node_bounds = node_bounds.union(&bounds[i]);
+        centroid_bounds = centroid_bounds.grow(centroids[i]);
+    }
+    let leaf = BvhNode {
+        bounds: node_bounds,
+        offset: offset as u32,
+        count: order.len() as u32,
+        axis: 0,
+    };
+    nodes.push(leaf);
+
+    let count = order.len();
+    let axis = centroid_bounds.largest_axis();
+    let axis_min = centroid_bounds.min[axis];
+    let axis_extent = centroid_bounds.max[axis] - axis_min;
+    if count <= 1 || axis_extent <= 0.0 {
+        return node_index;
+    }
+
+    let mut mid = 0;
+    if depth < MAX_SAH_DEPTH {
+        let bin_index = |i: usize| {
+            let b = (BIN_COUNT as f32 * (centroids[i][axis] - axis_min) / axis_extent) as usize;
+            b.min(BIN_COUNT - 1)
+        };
+        let mut bins = [Bin::default(); BIN_COUNT];
+        for &i in order.iter() {
+            let bin = &mut bins[bin_index(i)];
+            bin.bounds = bin.bounds.union(&bounds[i]);
+            bin.count += 1;
+        }
+
+        // Sweep from the right to find the cost of each split plane's right-hand side
+        let mut right_costs = [0.0f32; BIN_COUNT];
+        let mut right = Bin::default();
+        for b in (1..BIN_COUNT).rev() {
+            right.bounds = right.bounds.union(&bins[b].bounds);
+            right.count += bins[b].count;
+            right_costs[b] = right.bounds.surface_area() * right.count as f32;
+        }
+        let mut best_cost = f32::INFINITY;
+        let mut best_split = 0;
+        let mut left = Bin::default();
+        for b in 0..BIN_COUNT - 1 {
+            left.bounds = left.bounds.union(&bins[b].bounds);
+            left.count += bins[b].count;
+            let cost = left.bounds.surface_area() * left.count as f32 + right_costs[b + 1];
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = b;
+            }
+        }
+
+        let split_cost = TRAVERSAL_COST
+            + INTERSECTION_COST * best_cost / node_bounds.surface_area().max(f32::MIN_POSITIVE);
+        let leaf_cost = INTERSECTION_COST * count as f32;
+        if count <= MAX_LEAF_SIZE && leaf_cost <= split_cost {
+            return node_index;
+        }
+
+        mid = partition(order, |i| bin_index(i) <= best_split);
+    }
+    if mid == 0 || mid == count {
+        mid = count / 2;
+        order.select_nth_unstable_by(mid, |&a, &b| {
+            centroids[a][axis].partial_cmp(&centroids[b][axis]).unwrap()
+        });
+    }
+
+    let (left, right) = order.split_at_mut(mid);
+    build_recursive(bounds, centroids, left, offset, depth + 1, nodes);
+    let second = build_recursive(bounds, centroids, right, offset + mid, depth + 1, nodes);
+    nodes[node_index] = BvhNode {
+        bounds: node_bounds,
+        offset: second as u32,
+        count: 0,
+        axis: axis as u8,
+    };
+    node_index
+}
+
+fn partition<F>(order: &mut [usize], predicate: F) -> usize where F: Fn(usize) -> bool {
+    let mut mid = 0;
+    for i in 0..order.len() {
+        if predicate(order[i]) {
+            order.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::SmallRng;
+
+    // The nearer distance at which the ray hits the sphere, within the range
+    fn hit_sphere(r: &Ray, center: Vec3A, radius: f32, t_min: f32, t_max: f32) -> Option<f32> {
+        let oc = r.origin - center;
+        let a = r.direction.dot(r.direction);
+        let b = oc.dot(r.direction);
+        let c = oc.dot(oc) - radius * radius;
+        let discriminant = b * b - a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let t = (-b - discriminant.sqrt()) / a;
+        if t_min < t && t < t_max { Some(t) } else { None }
+    }
+
+    fn random_spheres(rng: &mut SmallRng, count: usize) -> Vec<(Vec3A, f32)> {
+        (0..count).map(|_| {
+            let center = Vec3A::new(rng.gen(), rng.gen(), rng.gen()) * 20.0 - Vec3A::splat(10.0);
+            (center, 0.1 + rng.gen::<f32>())
+        }).collect()
+    }
+
+    fn sphere_bounds(&(center, radius): &(Vec3A, f32)) -> Aabb {
+        Aabb::new(center - Vec3A::splat(radius), center + Vec3A::splat(radius))
+    }
+
+    fn random_ray(rng: &mut SmallRng) -> Ray {
+        let origin = Vec3A::new(rng.gen(), rng.gen(), rng.gen()) * 30.0 - Vec3A::splat(15.0);
+        let target = Vec3A::new(rng.gen(), rng.gen(), rng.gen()) * 10.0 - Vec3A::splat(5.0);
+        Ray::new(origin, (target - origin).normalize(), 0.0)
+    }
+
+    fn closest_by_brute_force(spheres: &[(Vec3A, f32)], r: &Ray) -> Option<f32> {
+        spheres.iter()
+            .filter_map(|&(center, radius)| hit_sphere(r, center, radius, 0.001, f32::MAX))
+            .fold(None, |closest, t| Some(closest.map_or(t, |c: f32| c.min(t))))
+    }
+
+    fn closest_by_bvh(bvh: &Bvh<(Vec3A, f32)>, r: &Ray) -> Option<f32> {
+        bvh.traverse(r, 0.001, f32::MAX, |&(center, radius), t_max| hit_sphere(r, center, radius, 0.001, t_max))
+    }
+
+    #[test]
+    fn traversal_finds_the_closest_hit() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let spheres = random_spheres(&mut rng, 500);
+        let bvh = Bvh::build(spheres.iter().map(|s| (sphere_bounds(s), *s)).collect());
+        assert_eq!(bvh.items().len(), spheres.len());
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let r = random_ray(&mut rng);
+            let expected = closest_by_brute_force(&spheres, &r);
+            assert_eq!(closest_by_bvh(&bvh, &r), expected);
+            hits += expected.is_some() as usize;
+        }
+        assert!(hits > 0);
+    }
+
+    #[test]
+    fn unbounded_items_are_always_visited() {
+        let bvh = Bvh::build(vec![
+            (Aabb::infinite(), 0),
+            (Aabb::new(Vec3A::splat(-1.0), Vec3A::splat(1.0)), 1),
+        ]);
+        assert_eq!(bvh.items(), &[1]);
+        assert!(!bvh.bounds().is_finite());
+        let away = Ray::new(Vec3A::splat(5.0), Vec3A::unit_x(), 0.0);
+        let mut visited = Vec::new();
+        bvh.traverse(&away, 0.001, f32::MAX, |&item, _| { visited.push(item); None });
+        assert_eq!(visited, vec![0]);
+    }
+
+    #[test]
+    fn refit_follows_moved_items() {
+        let mut rng = SmallRng::seed_from_u64(2);
+        let spheres = random_spheres(&mut rng, 200);
+        let mut bvh = Bvh::build(spheres.iter().enumerate().map(|(i, s)| (sphere_bounds(s), i)).collect());
+        let offset = Vec3A::new(3.0, -2.0, 1.0);
+        let moved: Vec<(Vec3A, f32)> = spheres.iter().map(|&(center, radius)| (center + offset, radius)).collect();
+        bvh.refit(|&i| sphere_bounds(&moved[i]));
+        for _ in 0..1000 {
+            let r = random_ray(&mut rng);
+            let found = bvh.traverse(&r, 0.001, f32::MAX, |&i, t_max| hit_sphere(&r, moved[i].0, moved[i].1, 0.001, t_max));
+            assert_eq!(found, closest_by_brute_force(&moved, &r));
+        }
+    }
+}
@@ -1,11 +1,88 @@
-use glam::Vec3A;
+use glam::{Mat3, Vec3, Vec3A};
 use std::default::Default;
 use std::f32;
 
 use ray::Ray;
-use utils::random_in_unit_disk;
+use sampler::Sampler;
+use utils::sample_unit_disk;
 
-#[derive(Debug)]
+/// The parameters from which a `Camera` is constructed, kept so that the camera can be rebuilt,
+/// for example when the output aspect ratio is known.
+#[derive(Clone, Copy, Debug)]
+pub struct CameraParams {
+    pub look_from: Vec3A,
+    pub look_at: Vec3A,
+    pub vup: Vec3A,
+    pub vfov: f32,
+    pub aperture: f32,
+    pub focus_dist: f32,
+    // The times at which the shutter opens and closes. Moving entities blur over the interval.
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+}
+
+impl Default for CameraParams {
+    fn default() -> CameraParams {
+        CameraParams {
+            look_from: Vec3A::new(13.0, 2.0, 3.0),
+            look_at: Vec3A::zero(),
+            vup: Vec3A::unit_y(),
+            vfov: 20.0,
+            aperture: 0.1,
+            focus_dist: 10.0,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+        }
+    }
+}
+
+impl CameraParams {
+    pub fn forward(&self) -> Vec3A {
+        (self.look_at - self.look_from).normalize()
+    }
+
+    pub fn right(&self) -> Vec3A {
+        self.forward().cross(self.vup).normalize()
+    }
+
+    /// Move both the eye and the point looked at, relative to the view direction.
+    pub fn fly(&mut self, forward: f32, right: f32, up: f32) {
+        let offset = forward * self.forward() + right * self.right() + up * self.vup.normalize();
+        self.look_from += offset;
+        self.look_at += offset;
+    }
+
+    /// Turn the view direction about the eye by angles in radians. Pitching is stopped short of
+    /// looking straight along `vup` where the camera basis would degenerate.
+    pub fn look(&mut self, yaw: f32, pitch: f32) {
+        let distance = (self.look_at - self.look_from).length();
+        let vup = self.vup.normalize();
+        let mut forward = Mat3::from_axis_angle(Vec3::from(vup), yaw) * self.forward();
+        let pitched = Mat3::from_axis_angle(Vec3::from(self.right()), pitch) * forward;
+        if pitched.dot(vup).abs() < 0.99 {
+            forward = pitched;
+        }
+        self.look_at = self.look_from + distance * forward.normalize();
+    }
+
+    pub fn zoom(&mut self, factor: f32) {
+        self.vfov = (self.vfov * factor).clamp(1.0, 170.0);
+    }
+
+    pub fn camera(&self, aspect: f32) -> Camera {
+        Camera::new(
+            self.look_from,
+            self.look_at,
+            self.vup,
+            self.vfov,
+            aspect,
+            self.aperture,
+            self.focus_dist,
+        ).with_shutter(self.shutter_open, self.shutter_close)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Camera {
     origin: Vec3A,
     lower_left_corner: Vec3A,
@@ -15,6 +92,8 @@ pub struct Camera {
     v: Vec3A,
     w: Vec3A,
     lens_radius: f32,
+    shutter_open: f32,
+    shutter_close: f32,
 }
 
 impl Default for Camera {
@@ -58,12 +137,29 @@ impl Camera {
             vertical: 2.0 * half_height * focus_dist * v,
             u, v, w,
             lens_radius: 0.5 * aperture,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let rd = self.lens_radius * random_in_unit_disk();
+    pub fn with_shutter(self, open: f32, close: f32) -> Camera {
+        Camera { shutter_open: open, shutter_close: close.max(open), ..self }
+    }
+
+    /// The interval over which rays are traced
+    pub fn shutter(&self) -> (f32, f32) {
+        (self.shutter_open, self.shutter_close)
+    }
+
+    pub fn get_ray(&self, s: f32, t: f32, sampler: &mut Sampler) -> Ray {
+        let rd = self.lens_radius * sample_unit_disk(sampler.next_2d());
         let offset = rd.x() * self.u + rd.y() * self.v;
+        // Only an open shutter takes a time sample, so that still images sample as before
+        let time = if self.shutter_close > self.shutter_open {
+            self.shutter_open + sampler.next_1d() * (self.shutter_close - self.shutter_open)
+        } else {
+            self.shutter_open
+        };
         Ray::new(
             self.origin + offset,
             self.lower_left_corner
@@ -71,6 +167,7 @@ impl Camera {
                 + t * self.vertical
                 - self.origin
                 - offset,
+            time,
         )
     }
 }
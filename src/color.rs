@@ -2,6 +2,7 @@ use glam::Vec3A;
 use std::convert::From;
 use std::fmt;
 use std::ops::{AddAssign, Div, Mul, MulAssign};
+use std::str::FromStr;
 
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Colorf32 {
@@ -16,9 +17,12 @@ impl Colorf32 {
         Colorf32 { r, g, b, a }
     }
 
-    pub fn as_argb8888(&self) -> (u8, u8, u8, u8) {
-        let c: Coloru8 = (*self).into();
-        (c.a, c.r, c.g, c.b)
+    pub fn luminance(&self) -> f32 {
+        luminance(self.rgb())
+    }
+
+    pub fn rgb(&self) -> Vec3A {
+        Vec3A::new(self.r, self.g, self.b)
     }
 }
 
@@ -112,13 +116,19 @@ impl From<Coloru8> for u32 {
     }
 }
 
+// Linear [0, 1] to 8 bits, rounding to nearest after adding `dither` in [-0.5, 0.5)
+pub fn quantize(value: f32, dither: f32) -> u8 {
+    (255.0 * value + 0.5 + dither).clamp(0.0, 255.0) as u8
+}
+
+/// sRGB encoding of linear color, clipped to [0, 1]
 impl From<Colorf32> for Coloru8 {
     fn from(color: Colorf32) -> Self {
         Coloru8 {
-            r: (255.99 * color.r.sqrt()) as u8,
-            g: (255.99 * color.g.sqrt()) as u8,
-            b: (255.99 * color.b.sqrt()) as u8,
-            a: (255.99 * color.a) as u8,
+            r: quantize(srgb_oetf(color.r), 0.0),
+            g: quantize(srgb_oetf(color.g), 0.0),
+            b: quantize(srgb_oetf(color.b), 0.0),
+            a: quantize(color.a, 0.0),
         }
     }
 }
@@ -134,3 +144,134 @@ impl From<Vec3A> for Colorf32 {
         Colorf32::new(color.x(), color.y(), color.z(), 1.0)
     }
 }
+
+/// The Rec. 709 luminance of a linear color
+pub fn luminance(color: Vec3A) -> f32 {
+    0.2126 * color.x() + 0.7152 * color.y() + 0.0722 * color.z()
+}
+
+/// The inverse of `srgb_oetf`, from encoded to linear, for values in [0, 1]
+pub fn srgb_eotf(encoded: f32) -> f32 {
+    if encoded <= 0.040_45 {
+        encoded / 12.92
+    } else {
+        ((encoded + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// The sRGB opto-electronic transfer function, from linear to encoded, for values in [0, 1]
+pub fn srgb_oetf(linear: f32) -> f32 {
+    let linear = linear.clamp(0.0, 1.0);
+    if linear <= 0.003_130_8 {
+        12.92 * linear
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Operators that map linear scene radiance into the [0, 1] display range
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Tonemap {
+    // Clip at 1
+    #[default]
+    None,
+    // x / (1 + x) per channel
+    Reinhard,
+    // Stephen Hill's fit of the ACES reference rendering and sRGB output transforms
+    Aces,
+    // Troy Sobotka's AgX, as approximated by Benjamin Wrensch, which desaturates highlights
+    // towards white instead of skewing their hue
+    Agx,
+}
+
+impl Tonemap {
+    pub fn next(self) -> Tonemap {
+        match self {
+            Tonemap::None => Tonemap::Reinhard,
+            Tonemap::Reinhard => Tonemap::Aces,
+            Tonemap::Aces => Tonemap::Agx,
+            Tonemap::Agx => Tonemap::None,
+        }
+    }
+}
+
+impl FromStr for Tonemap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Tonemap, String> {
+        match s {
+            "none" => Ok(Tonemap::None),
+            "reinhard" => Ok(Tonemap::Reinhard),
+            "aces" => Ok(Tonemap::Aces),
+            "agx" => Ok(Tonemap::Agx),
+            _ => Err(format!("unknown tonemapping operator '{}'", s)),
+        }
+    }
+}
+
+// Matrices are given by rows
+fn transform(m: &[[f32; 3]; 3], v: Vec3A) -> Vec3A {
+    Vec3A::new(
+        m[0][0] * v.x() + m[0][1] * v.y() + m[0][2] * v.z(),
+        m[1][0] * v.x() + m[1][1] * v.y() + m[1][2] * v.z(),
+        m[2][0] * v.x() + m[2][1] * v.y() + m[2][2] * v.z(),
+    )
+}
+
+fn map(v: Vec3A, f: impl Fn(f32) -> f32) -> Vec3A {
+    Vec3A::new(f(v.x()), f(v.y()), f(v.z()))
+}
+
+const ACES_INPUT: [[f32; 3]; 3] = [
+    [0.59719, 0.35458, 0.04823],
+    [0.07600, 0.90834, 0.01566],
+    [0.02840, 0.13383, 0.83777],
+];
+const ACES_OUTPUT: [[f32; 3]; 3] = [
+    [1.60475, -0.53108, -0.07367],
+    [-0.10208, 1.10813, -0.00605],
+    [-0.00327, -0.07276, 1.07602],
+];
+
+fn aces(color: Vec3A) -> Vec3A {
+    let v = transform(&ACES_INPUT, color);
+    let v = map(v, |v| {
+        (v * (v + 0.024_578_6) - 0.000_090_537) / (v * (0.983_729 * v + 0.432_951) + 0.238_081)
+    });
+    transform(&ACES_OUTPUT, v)
+}
+
+const AGX_INSET: [[f32; 3]; 3] = [
+    [0.842_479_06, 0.078_433_6, 0.079_223_745],
+    [0.042_328_242, 0.878_468_6, 0.079_166_13],
+    [0.042_375_65, 0.078_433_6, 0.879_143],
+];
+const AGX_OUTSET: [[f32; 3]; 3] = [
+    [1.196_879, -0.098_020_88, -0.099_029_74],
+    [-0.052_896_85, 1.151_903_1, -0.098_961_18],
+    [-0.052_971_635, -0.098_043_45, 1.151_073_7],
+];
+const AGX_MIN_EV: f32 = -12.473_93;
+const AGX_MAX_EV: f32 = 4.026_069;
+
+fn agx(color: Vec3A) -> Vec3A {
+    let v = transform(&AGX_INSET, color);
+    let v = map(v, |v| {
+        let x = ((v.max(1e-10).log2() - AGX_MIN_EV) / (AGX_MAX_EV - AGX_MIN_EV)).clamp(0.0, 1.0);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.002_32
+    });
+    // The curve produces display encoded values, so linearise them again for the sRGB encoding
+    map(transform(&AGX_OUTSET, v), |v| v.max(0.0).powf(2.2))
+}
+
+/// Map linear radiance into the [0, 1] display range. Results may still need clipping.
+pub fn tonemap(operator: Tonemap, color: Vec3A) -> Vec3A {
+    match operator {
+        Tonemap::None => color,
+        Tonemap::Reinhard => map(color, |c| c / (1.0 + c)),
+        Tonemap::Aces => aces(color),
+        Tonemap::Agx => agx(color),
+    }
+}
@@ -1,4 +1,4 @@
-use glam::Vec3A;
+use glam::{Mat3, Vec3, Vec3A};
 use specs::prelude::*;
 
 use color::Colorf32;
@@ -43,6 +43,65 @@ impl Component for SampleCount {
     type Storage = VecStorage<Self>;
 }
 
+/// The sum of the squared luminance of a pixel's samples, from which with `PixelColor` and
+/// `SampleCount` the variance of the pixel's estimate is derived
+#[derive(Clone, Copy)]
+pub struct PixelSecondMoment(pub f32);
+
+impl Component for PixelSecondMoment {
+    type Storage = VecStorage<Self>;
+}
+
+/// The estimated relative standard error of a pixel's mean luminance
+#[derive(Clone, Copy)]
+pub struct PixelError(pub f32);
+
+impl Component for PixelError {
+    type Storage = VecStorage<Self>;
+}
+
+/// Features of the first surface seen through a pixel, for denoising, debugging and compositing.
+/// Albedo, normal and depth are summed over the pixel's samples like `PixelColor`.
+#[derive(Clone, Copy)]
+pub struct PixelAovs {
+    pub albedo: Vec3A,
+    // World space shading normal, zero where the camera ray escaped
+    pub normal: Vec3A,
+    // Distance from the camera, zero where the camera ray escaped
+    pub depth: f32,
+    // The id of the entity hit by the pixel's first sample, NO_OBJECT for the background
+    pub object_id: u32,
+}
+
+pub const NO_OBJECT: u32 = u32::MAX;
+
+impl Default for PixelAovs {
+    fn default() -> PixelAovs {
+        PixelAovs {
+            albedo: Vec3A::zero(),
+            normal: Vec3A::zero(),
+            depth: 0.0,
+            object_id: NO_OBJECT,
+        }
+    }
+}
+
+impl PixelAovs {
+    /// Add the features of one sample. `first` is whether it is the pixel's first sample.
+    pub fn accumulate(&mut self, sample: &PixelAovs, first: bool) {
+        self.albedo += sample.albedo;
+        self.normal += sample.normal;
+        self.depth += sample.depth;
+        if first {
+            self.object_id = sample.object_id;
+        }
+    }
+}
+
+impl Component for PixelAovs {
+    type Storage = VecStorage<Self>;
+}
+
 pub struct Position(pub Vec3A);
 
 pub fn position(x: f32, y: f32, z: f32) -> Position {
@@ -50,22 +109,107 @@ pub fn position(x: f32, y: f32, z: f32) -> Position {
 }
 
 impl Component for Position {
-    type Storage = VecStorage<Self>;
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
+/// The rate at which an entity moves, in units per unit of time. It is at its `Position` at time
+/// zero and moves while the camera's shutter is open.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Velocity(pub Vec3A);
+
+impl Velocity {
+    /// The position at `time` of an entity that is at `position` at time zero
+    pub fn position_at(&self, position: &Position, time: f32) -> Position {
+        Position(position.0 + time * self.0)
+    }
+}
+
+impl Component for Velocity {
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
+/// A rotation, scale or shear of an entity's hitable about its `Position`. Rays are transformed
+/// into the hitable's own space to be intersected and the hits are transformed back.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    matrix: Mat3,
+    inverse: Mat3,
+    // The inverse transpose, which keeps normals perpendicular to the transformed surface
+    normal_matrix: Mat3,
+}
+
+impl Transform {
+    pub fn new(matrix: Mat3) -> Transform {
+        debug_assert!(matrix.determinant() != 0.0, "transform matrix is singular");
+        let inverse = matrix.inverse();
+        Transform { matrix, inverse, normal_matrix: inverse.transpose() }
+    }
+
+    pub fn identity() -> Transform {
+        Transform::new(Mat3::identity())
+    }
+
+    /// An anticlockwise rotation by `degrees` about `axis`
+    pub fn rotation(axis: Vec3A, degrees: f32) -> Transform {
+        Transform::new(Mat3::from_axis_angle(Vec3::from(axis.normalize()), degrees.to_radians()))
+    }
+
+    pub fn scale(scale: Vec3A) -> Transform {
+        Transform::new(Mat3::from_scale(Vec3::from(scale)))
+    }
+
+    /// This transform followed by `other`
+    pub fn then(&self, other: &Transform) -> Transform {
+        Transform::new(other.matrix * self.matrix)
+    }
+
+    pub fn to_world(&self, v: Vec3A) -> Vec3A {
+        self.matrix * v
+    }
+
+    pub fn to_object(&self, v: Vec3A) -> Vec3A {
+        self.inverse * v
+    }
+
+    /// The world space unit normal of a surface with the object space normal `n`
+    pub fn normal_to_world(&self, n: Vec3A) -> Vec3A {
+        (self.normal_matrix * n).normalize()
+    }
+
+    /// The factor by which the area of a flat surface with unit normal `n` grows in world space
+    pub fn area_scale(&self, n: Vec3A) -> f32 {
+        self.matrix.determinant().abs() * (self.normal_matrix * n).length()
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform::identity()
+    }
+}
+
+impl Component for Transform {
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
 }
 
 impl Component for Hitable {
-    type Storage = VecStorage<Self>;
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
 }
 
 impl Component for Material {
-    type Storage = VecStorage<Self>;
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
 }
 
 pub fn register_components(world: &mut World) {
     world.register::<PixelPosition>();
     world.register::<PixelColor>();
     world.register::<SampleCount>();
+    world.register::<PixelSecondMoment>();
+    world.register::<PixelError>();
+    world.register::<PixelAovs>();
     world.register::<Position>();
+    world.register::<Transform>();
+    world.register::<Velocity>();
     world.register::<Hitable>();
     world.register::<Material>();
 }
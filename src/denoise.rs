@@ -0,0 +1,200 @@
+use glam::Vec3A;
+use rayon::prelude::*;
+
+use color::luminance;
+
+use std::mem;
+
+// The 1D B3 spline kernel, from the centre outwards
+const KERNEL: [f32; 3] = [3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+// Albedo below this is not divided out, so that black surfaces do not amplify noise
+const MIN_ALBEDO: f32 = 0.01;
+
+/// Settings of the edge-avoiding À-trous wavelet filter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Denoise {
+    pub enabled: bool,
+    // Each iteration doubles the spacing of the taps, so the filter covers 2^(iterations + 2) pixels
+    pub iterations: u32,
+    // How many standard deviations of a pixel's noise a neighbour's luminance may differ by
+    pub sigma_luminance: f32,
+    // The exponent of the cosine between normals
+    pub sigma_normal: i32,
+    // How far off the local depth gradient a neighbour's depth may be
+    pub sigma_depth: f32,
+}
+
+impl Default for Denoise {
+    fn default() -> Denoise {
+        Denoise {
+            enabled: false,
+            iterations: 5,
+            sigma_luminance: 4.0,
+            sigma_normal: 128,
+            sigma_depth: 1.0,
+        }
+    }
+}
+
+/// The mean features of a pixel, the inputs of the filter
+#[derive(Clone, Copy, Debug)]
+pub struct PixelFeatures {
+    pub color: Vec3A,
+    // The variance of the mean luminance
+    pub variance: f32,
+    pub albedo: Vec3A,
+    // Zero where the camera rays escaped
+    pub normal: Vec3A,
+    pub depth: f32,
+    pub sample_count: f32,
+}
+
+// The geometry that guides the filter at a pixel, with a zero normal for the background
+#[derive(Clone, Copy)]
+struct Surface {
+    normal: Vec3A,
+    depth: f32,
+    // The absolute change in depth per pixel along x and y
+    gradient: (f32, f32),
+}
+
+// The illumination of a pixel and the variance of its luminance, None where it is not yet known
+type Illumination = Option<(Vec3A, f32)>;
+
+fn demodulate(albedo: Vec3A) -> Vec3A {
+    albedo.max(Vec3A::splat(MIN_ALBEDO))
+}
+
+fn surfaces(width: usize, height: usize, pixels: &[PixelFeatures]) -> Vec<Surface> {
+    let depth = |x: usize, y: usize| {
+        let p = &pixels[y * width + x];
+        if p.normal == Vec3A::zero() { None } else { Some(p.depth) }
+    };
+    // The smaller of the one-sided differences, so that the gradient does not straddle edges
+    let difference = |d: f32, a: Option<f32>, b: Option<f32>| {
+        match (a, b) {
+            (Some(a), Some(b)) => (d - a).abs().min((b - d).abs()),
+            (Some(n), None) | (None, Some(n)) => (d - n).abs(),
+            (None, None) => 0.0,
+        }
+    };
+    let mut surfaces = Vec::with_capacity(pixels.len());
+    for y in 0..height {
+        for x in 0..width {
+            let p = &pixels[y * width + x];
+            let d = p.depth;
+            let left = if x > 0 { depth(x - 1, y) } else { None };
+            let right = if x + 1 < width { depth(x + 1, y) } else { None };
+            let up = if y > 0 { depth(x, y - 1) } else { None };
+            let down = if y + 1 < height { depth(x, y + 1) } else { None };
+            surfaces.push(Surface {
+                normal: if p.normal == Vec3A::zero() { p.normal } else { p.normal.normalize() },
+                depth: d,
+                gradient: (difference(d, left, right), difference(d, up, down)),
+            });
+        }
+    }
+    surfaces
+}
+
+// How alike two pixels' geometry is, as a weight in [0, 1] and the exponent of a further factor
+fn geometry_weight(settings: &Denoise, p: &Surface, q: &Surface, offset: (f32, f32)) -> (f32, f32) {
+    match (p.normal == Vec3A::zero(), q.normal == Vec3A::zero()) {
+        (true, true) => (1.0, 0.0),
+        (false, false) => {
+            let w_normal = p.normal.dot(q.normal).max(0.0).powi(settings.sigma_normal);
+            let expected = p.gradient.0 * offset.0.abs() + p.gradient.1 * offset.1.abs();
+            (w_normal, (p.depth - q.depth).abs() / (settings.sigma_depth * expected + 1e-3 * p.depth + 1e-6))
+        },
+        _ => (0.0, 0.0),
+    }
+}
+
+fn filter_iteration(
+    settings: &Denoise,
+    width: usize,
+    height: usize,
+    step: usize,
+    surfaces: &[Surface],
+    input: &[Illumination],
+    output: &mut [Illumination],
+) {
+    output.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        for (x, out) in row.iter_mut().enumerate() {
+            let i = y * width + x;
+            let p = &surfaces[i];
+            // Pixels that have no samples yet are filled in from their neighbours
+            let centre = input[i].map(|(color, variance)| {
+                (luminance(color), 1.0 / (settings.sigma_luminance * variance.sqrt() + 1e-4))
+            });
+            let mut sum_weight = 0.0;
+            let mut sum_color = Vec3A::zero();
+            let mut sum_variance = 0.0;
+            for dy in -2i32..=2 {
+                let qy = y as i32 + dy * step as i32;
+                if qy < 0 || qy >= height as i32 {
+                    continue;
+                }
+                for dx in -2i32..=2 {
+                    let qx = x as i32 + dx * step as i32;
+                    if qx < 0 || qx >= width as i32 {
+                        continue;
+                    }
+                    let j = qy as usize * width + qx as usize;
+                    let (color, variance) = match input[j] {
+                        Some(q) => q,
+                        None => continue,
+                    };
+                    let offset = ((dx * step as i32) as f32, (dy * step as i32) as f32);
+                    let (w_geometry, mut exponent) = geometry_weight(settings, p, &surfaces[j], offset);
+                    if w_geometry == 0.0 {
+                        continue;
+                    }
+                    if let Some((l, inv_sigma)) = centre {
+                        exponent += (l - luminance(color)).abs() * inv_sigma;
+                    }
+                    let weight = KERNEL[dx.unsigned_abs() as usize] * KERNEL[dy.unsigned_abs() as usize]
+                        * w_geometry * (-exponent).exp();
+                    if weight > 0.0 {
+                        sum_weight += weight;
+                        sum_color += weight * color;
+                        sum_variance += weight * weight * variance;
+                    }
+                }
+            }
+            *out = if sum_weight > 0.0 {
+                Some((sum_color / sum_weight, sum_variance / (sum_weight * sum_weight)))
+            } else {
+                input[i]
+            };
+        }
+    });
+}
+
+/// Filter the colour of an image, given as features row by row from the top. The illumination,
+/// that is colour divided by albedo, is blurred across pixels with similar normals, depths and,
+/// relative to the noise, luminance, and then multiplied by the albedo again so that texture
+/// detail survives. Pixels without samples are filled in from their neighbours.
+pub fn denoise_image(settings: &Denoise, width: usize, height: usize, pixels: &[PixelFeatures]) -> Vec<Vec3A> {
+    let surfaces = surfaces(width, height, pixels);
+    let mut illumination: Vec<Illumination> = pixels.iter()
+        .map(|p| {
+            if p.sample_count < 1.0 {
+                return None;
+            }
+            let albedo = demodulate(p.albedo);
+            // Pixels with one sample have no variance estimate, which leaves them to the geometry
+            let variance = if p.sample_count < 2.0 { f32::INFINITY } else { p.variance / luminance(albedo).powi(2) };
+            Some((p.color / albedo, variance))
+        })
+        .collect();
+    let mut filtered = illumination.clone();
+    for iteration in 0..settings.iterations {
+        filter_iteration(settings, width, height, 1 << iteration, &surfaces, &illumination, &mut filtered);
+        mem::swap(&mut illumination, &mut filtered);
+    }
+    illumination.iter()
+        .zip(pixels.iter())
+        .map(|(i, p)| i.map_or(Vec3A::zero(), |(color, _)| color * demodulate(p.albedo)))
+        .collect()
+}
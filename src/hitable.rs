@@ -1,23 +1,45 @@
-use glam::Vec3A;
+use glam::{Vec2, Vec3A};
 
-use components::Position;
+use aabb::Aabb;
+use bvh::Bvh;
+use components::{Position, Transform};
 use material::Material;
+use mesh::{intersect_triangle, Mesh};
 use ray::Ray;
+use utils::{orthonormal_basis, sample_unit_disk};
+use volume::DensityField;
+
+use std::f32::consts::PI;
+use std::sync::Arc;
 
 #[derive(Clone, Copy)]
 pub struct HitRecord {
     pub t: f32,
     pub p: Vec3A,
+    // Unit normal on the side of the surface that the ray arrived from
     pub normal: Vec3A,
+    // Whether the ray hit the front of the surface, the side its own normal points to, such as the
+    // outside of a sphere or the anticlockwise side of a triangle
+    pub front_face: bool,
+    // Surface texture coordinates, each in [0, 1] except on planes and where a mesh says otherwise
+    pub uv: Vec2,
     pub material: Option<Material>,
 }
 
 impl HitRecord {
-    pub fn new(t: f32, p: Vec3A, normal: Vec3A, material: Option<Material>) -> HitRecord {
-        HitRecord { t, p, normal, material }
+    pub fn new(t: f32, p: Vec3A, normal: Vec3A, uv: Vec2, material: Option<Material>) -> HitRecord {
+        HitRecord { t, p, normal, front_face: true, uv, material }
     }
 }
 
+// Longitude and latitude of a point on the unit sphere, with v = 0 at the bottom (-y) and u
+// increasing anticlockwise seen from above, starting from -x
+fn sphere_uv(p: Vec3A) -> Vec2 {
+    let theta = (-p.y()).clamp(-1.0, 1.0).acos();
+    let phi = (-p.z()).atan2(p.x()) + PI;
+    Vec2::new(phi / (2.0 * PI), theta / PI)
+}
+
 pub struct Sphere {
     pub radius: f32,
 }
@@ -26,12 +48,305 @@ pub fn sphere(radius: f32) -> Hitable {
     Hitable::Sphere(Sphere { radius })
 }
 
+/// A single triangle, with vertices relative to the entity's position
+pub struct Triangle {
+    pub v0: Vec3A,
+    pub v1: Vec3A,
+    pub v2: Vec3A,
+}
+
+pub fn triangle(v0: Vec3A, v1: Vec3A, v2: Vec3A) -> Hitable {
+    Hitable::Triangle(Triangle { v0, v1, v2 })
+}
+
+pub fn mesh(mesh: Mesh) -> Hitable {
+    Hitable::Mesh(Arc::new(mesh))
+}
+
+/// An infinite plane through the entity's position
+pub struct Plane {
+    pub normal: Vec3A,
+}
+
+pub fn plane(normal: Vec3A) -> Hitable {
+    Hitable::Plane(Plane { normal: normal.normalize() })
+}
+
+/// A parallelogram with one corner at the entity's position and the edges `u` and `v` from it.
+/// It faces towards `u` cross `v`.
+pub struct Quad {
+    pub u: Vec3A,
+    pub v: Vec3A,
+}
+
+pub fn quad(u: Vec3A, v: Vec3A) -> Hitable {
+    Hitable::Quad(Quad { u, v })
+}
+
+/// An axis-aligned rectangle with a corner at the entity's position, `width` along the first of
+/// the other two axes in x, y, z order and `height` along the second, facing along `axis` (0 for
+/// x, 1 for y, 2 for z) or against it if `width` or `height` is negative
+pub fn rect(axis: usize, width: f32, height: f32) -> Hitable {
+    let mut u = Vec3A::zero();
+    let mut v = Vec3A::zero();
+    u[(axis + 1) % 3] = width;
+    v[(axis + 2) % 3] = height;
+    quad(u, v)
+}
+
+/// A flat disk centred on the entity's position
+pub struct Disk {
+    pub normal: Vec3A,
+    pub radius: f32,
+}
+
+pub fn disk(normal: Vec3A, radius: f32) -> Hitable {
+    Hitable::Disk(Disk { normal: normal.normalize(), radius })
+}
+
+/// An axis-aligned box between two corners relative to the entity's position, with normals
+/// facing out
+pub struct Cuboid {
+    pub min: Vec3A,
+    pub max: Vec3A,
+}
+
+pub fn cuboid(min: Vec3A, max: Vec3A) -> Hitable {
+    Hitable::Cuboid(Cuboid { min: min.min(max), max: min.max(max) })
+}
+
+/// A hitable within a group, placed relative to the group's position. Without a material of its
+/// own it takes that of the group's entity.
+pub struct GroupItem {
+    pub position: Position,
+    pub hitable: Hitable,
+    pub material: Option<Material>,
+}
+
+/// A collection of hitables in a BVH of their own, so that together they can be one entity and
+/// be instanced as a whole
+pub struct Group {
+    bvh: Bvh<GroupItem>,
+}
+
+impl Group {
+    pub fn new(items: Vec<GroupItem>) -> Group {
+        let primitives = items.into_iter()
+            .map(|item| (bounding_box(&item.position, None, &item.hitable), item))
+            .collect();
+        Group { bvh: Bvh::build(primitives) }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.bvh.bounds()
+    }
+
+    /// Intersect a ray given in the group's own coordinate space
+    pub fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let mut closest = None;
+        self.bvh.traverse(r, t_min, t_max, |item, t_max| {
+            let mut rec = surface_hit(&item.position, None, &item.hitable, r, t_min, t_max)?;
+            // An item's material replaces any within it
+            rec.material = item.material.or(rec.material);
+            closest = Some(rec);
+            Some(rec.t)
+        });
+        closest
+    }
+}
+
+pub fn group(items: Vec<GroupItem>) -> Hitable {
+    Hitable::Group(Arc::new(Group::new(items)))
+}
+
+/// Geometry shared between any number of entities, each placing it by its own position and
+/// transform. The material of the entity or group item replaces any within the geometry.
+pub fn instance(geometry: &Arc<Hitable>) -> Hitable {
+    Hitable::Instance(geometry.clone())
+}
+
+/// A volume of constant `density` filling a closed boundary, which scatters light with the
+/// entity's material, normally a `Volume`. Rays are not stopped at the boundary, instead the path
+/// tracer samples where within the volume they collide.
+pub struct ConstantMedium {
+    pub boundary: Box<Hitable>,
+    pub density: f32,
+}
+
+pub fn constant_medium(boundary: Hitable, density: f32) -> Hitable {
+    Hitable::ConstantMedium(ConstantMedium { boundary: Box::new(boundary), density })
+}
+
+/// A volume filling a closed boundary whose density is `density` times a field that varies
+/// through the hitable's own space. Collisions are sampled by delta tracking against the largest
+/// density within it.
+pub struct HeterogeneousMedium {
+    pub boundary: Box<Hitable>,
+    pub field: DensityField,
+    pub density: f32,
+}
+
+pub fn heterogeneous_medium(boundary: Hitable, field: DensityField, density: f32) -> Hitable {
+    Hitable::HeterogeneousMedium(HeterogeneousMedium { boundary: Box::new(boundary), field, density })
+}
+
 pub enum Hitable {
+    ConstantMedium(ConstantMedium),
+    Cuboid(Cuboid),
+    Disk(Disk),
+    Group(Arc<Group>),
+    HeterogeneousMedium(HeterogeneousMedium),
+    Instance(Arc<Hitable>),
+    Mesh(Arc<Mesh>),
+    Plane(Plane),
+    Quad(Quad),
     Sphere(Sphere),
+    Triangle(Triangle),
 }
 
-pub fn hit(position: &Position, hitable: &Hitable, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+// The distance along the ray to the plane through `point` with normal `normal`
+fn intersect_plane(r: &Ray, point: Vec3A, normal: Vec3A, t_min: f32, t_max: f32) -> Option<f32> {
+    let denominator = normal.dot(r.direction);
+    if denominator.abs() < 1e-12 {
+        return None;
+    }
+    let t = normal.dot(point - r.origin) / denominator;
+    if t_min < t && t < t_max {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+// The entry and exit distances of a ray through a box, and the axes of the faces crossed
+fn intersect_box(r: &Ray, min: Vec3A, max: Vec3A) -> ((f32, usize), (f32, usize)) {
+    let mut near = (f32::NEG_INFINITY, 0);
+    let mut far = (f32::INFINITY, 0);
+    for axis in 0..3 {
+        let inv_direction = 1.0 / r.direction[axis];
+        let t0 = (min[axis] - r.origin[axis]) * inv_direction;
+        let t1 = (max[axis] - r.origin[axis]) * inv_direction;
+        let (t0, t1) = if t0 <= t1 { (t0, t1) } else { (t1, t0) };
+        if t0 > near.0 {
+            near = (t0, axis);
+        }
+        if t1 < far.0 {
+            far = (t1, axis);
+        }
+    }
+    (near, far)
+}
+
+// The normal of a quad or disk relative to its position
+fn flat_normal(hitable: &Hitable) -> Option<Vec3A> {
     match hitable {
+        Hitable::Quad(h) => Some(h.u.cross(h.v).normalize()),
+        Hitable::Disk(h) => Some(h.normal),
+        _ => None,
+    }
+}
+
+// The area of a quad or disk
+fn area(hitable: &Hitable, transform: Option<&Transform>) -> Option<f32> {
+    let area = match hitable {
+        Hitable::Quad(h) => h.u.cross(h.v).length(),
+        Hitable::Disk(h) => PI * h.radius * h.radius,
+        _ => return None,
+    };
+    match transform {
+        Some(transform) => Some(area * transform.area_scale(flat_normal(hitable)?)),
+        None => Some(area),
+    }
+}
+
+// The normal of a quad or disk and the point on it for a pair of uniform numbers. Linear
+// transforms scale the area evenly so the points stay uniformly distributed.
+fn area_sample(position: &Position, transform: Option<&Transform>, hitable: &Hitable, u: (f32, f32)) -> Option<(Vec3A, Vec3A)> {
+    let normal = flat_normal(hitable)?;
+    let offset = match hitable {
+        Hitable::Quad(h) => u.0 * h.u + u.1 * h.v,
+        Hitable::Disk(h) => {
+            let (a, b) = orthonormal_basis(h.normal);
+            let d = sample_unit_disk(u);
+            h.radius * (d.x() * a + d.y() * b)
+        },
+        _ => return None,
+    };
+    match transform {
+        Some(transform) => Some((transform.normal_to_world(normal), position.0 + transform.to_world(offset))),
+        None => Some((normal, position.0 + offset)),
+    }
+}
+
+/// Intersect a ray with the hitable placed at `position`, rotated and scaled about it by
+/// `transform` if there is one. The normal of the hit faces the ray.
+pub fn hit(position: &Position, transform: Option<&Transform>, hitable: &Hitable, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    let mut rec = surface_hit(position, transform, hitable, r, t_min, t_max)?;
+    rec.front_face = r.direction.dot(rec.normal) < 0.0;
+    if !rec.front_face {
+        rec.normal = -rec.normal;
+    }
+    Some(rec)
+}
+
+// The hit with the surface's own normal, whichever side the ray arrives from
+fn surface_hit(position: &Position, transform: Option<&Transform>, hitable: &Hitable, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    let transform = match transform {
+        Some(transform) => transform,
+        None => return object_hit(position, hitable, r, t_min, t_max),
+    };
+    // The direction is not renormalized so that distances along the ray are the same in both spaces
+    let object_ray = Ray::new(
+        transform.to_object(r.origin - position.0),
+        transform.to_object(r.direction),
+        r.time,
+    );
+    let mut rec = object_hit(&Position(Vec3A::zero()), hitable, &object_ray, t_min, t_max)?;
+    rec.p = r.at_t(rec.t);
+    rec.normal = transform.normal_to_world(rec.normal);
+    Some(rec)
+}
+
+pub fn is_medium(hitable: &Hitable) -> bool {
+    matches!(hitable, Hitable::ConstantMedium(_) | Hitable::HeterogeneousMedium(_))
+}
+
+/// The part of the ray between `t_min` and `t_max` that lies within a medium, and the medium's
+/// density, or the largest density within it if it varies. Boundaries are assumed to be convex.
+pub fn medium_interval(position: &Position, transform: Option<&Transform>, hitable: &Hitable, r: &Ray, t_min: f32, t_max: f32) -> Option<(f32, f32, f32)> {
+    let (boundary, majorant) = match hitable {
+        Hitable::ConstantMedium(h) => (&h.boundary, h.density),
+        Hitable::HeterogeneousMedium(h) => (&h.boundary, h.density * h.field.max()),
+        _ => return None,
+    };
+    // The boundary is entered before the ray's origin when the origin is inside it
+    let enter = hit(position, transform, boundary, r, f32::NEG_INFINITY, f32::INFINITY)?;
+    let exit = hit(position, transform, boundary, r, enter.t + 1e-4, f32::INFINITY)?;
+    let (t0, t1) = (enter.t.max(t_min), exit.t.min(t_max));
+    if t0 < t1 {
+        Some((t0, t1, majorant))
+    } else {
+        None
+    }
+}
+
+/// The density of a medium at the point `p` within it
+pub fn medium_density(position: &Position, transform: Option<&Transform>, hitable: &Hitable, p: Vec3A) -> f32 {
+    match hitable {
+        Hitable::ConstantMedium(h) => h.density,
+        Hitable::HeterogeneousMedium(h) => {
+            let offset = p - position.0;
+            let p = transform.map_or(offset, |transform| transform.to_object(offset));
+            h.density * h.field.value(p)
+        },
+        _ => 0.0,
+    }
+}
+
+fn object_hit(position: &Position, hitable: &Hitable, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    match hitable {
+        // Media have no surface, see `medium_interval`
+        Hitable::ConstantMedium(_) | Hitable::HeterogeneousMedium(_) => None,
         Hitable::Sphere(h) => {
             let oc = r.origin - position.0;
             let a = r.direction.length_squared();
@@ -42,15 +357,231 @@ pub fn hit(position: &Position, hitable: &Hitable, r: &Ray, t_min: f32, t_max: f
                 let temp = (-b - discriminant_ish.sqrt()) / a;
                 if t_min < temp && temp < t_max {
                     let p = r.at_t(temp);
-                    return Some(HitRecord::new(temp, p, (p - position.0) / h.radius, None));
+                    let uv = sphere_uv((p - position.0) / h.radius.abs());
+                    return Some(HitRecord::new(temp, p, (p - position.0) / h.radius, uv, None));
                 }
                 let temp = (-b + discriminant_ish.sqrt()) / a;
                 if t_min < temp && temp < t_max {
                     let p = r.at_t(temp);
-                    return Some(HitRecord::new(temp, p, (p - position.0) / h.radius, None));
+                    let uv = sphere_uv((p - position.0) / h.radius.abs());
+                    return Some(HitRecord::new(temp, p, (p - position.0) / h.radius, uv, None));
                 }
             }
             None
         }
+        Hitable::Triangle(h) => {
+            let o = position.0;
+            let (t, b1, b2) = intersect_triangle(r, o + h.v0, o + h.v1, o + h.v2, t_min, t_max)?;
+            let normal = (h.v1 - h.v0).cross(h.v2 - h.v0).normalize();
+            Some(HitRecord::new(t, r.at_t(t), normal, Vec2::new(b1, b2), None))
+        }
+        Hitable::Mesh(h) => {
+            let local = Ray::new(r.origin - position.0, r.direction, r.time);
+            let mut rec = h.hit(&local, t_min, t_max)?;
+            rec.p += position.0;
+            Some(rec)
+        }
+        Hitable::Group(h) => {
+            let local = Ray::new(r.origin - position.0, r.direction, r.time);
+            let mut rec = h.hit(&local, t_min, t_max)?;
+            rec.p += position.0;
+            Some(rec)
+        }
+        Hitable::Instance(h) => surface_hit(position, None, h, r, t_min, t_max),
+        Hitable::Plane(h) => {
+            let t = intersect_plane(r, position.0, h.normal, t_min, t_max)?;
+            let p = r.at_t(t);
+            let (a, b) = orthonormal_basis(h.normal);
+            let q = p - position.0;
+            Some(HitRecord::new(t, p, h.normal, Vec2::new(q.dot(a), q.dot(b)), None))
+        }
+        Hitable::Quad(h) => {
+            let n = h.u.cross(h.v);
+            let t = intersect_plane(r, position.0, n, t_min, t_max)?;
+            let p = r.at_t(t);
+            // The coordinates of the hit along each edge
+            let w = n / n.length_squared();
+            let q = p - position.0;
+            let alpha = w.dot(q.cross(h.v));
+            let beta = w.dot(h.u.cross(q));
+            if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+                return None;
+            }
+            Some(HitRecord::new(t, p, n.normalize(), Vec2::new(alpha, beta), None))
+        }
+        Hitable::Disk(h) => {
+            let t = intersect_plane(r, position.0, h.normal, t_min, t_max)?;
+            let p = r.at_t(t);
+            let q = p - position.0;
+            let distance_squared = q.length_squared();
+            if distance_squared > h.radius * h.radius {
+                return None;
+            }
+            // Polar coordinates, the distance from the centre and the angle about the normal
+            let (a, b) = orthonormal_basis(h.normal);
+            let angle = q.dot(b).atan2(q.dot(a));
+            let uv = Vec2::new(distance_squared.sqrt() / h.radius, angle / (2.0 * PI) + 0.5);
+            Some(HitRecord::new(t, p, h.normal, uv, None))
+        }
+        Hitable::Cuboid(h) => {
+            let (min, max) = (position.0 + h.min, position.0 + h.max);
+            let (near, far) = intersect_box(r, min, max);
+            if near.0 > far.0 {
+                return None;
+            }
+            // Rays starting inside hit the far side
+            let (t, axis) = if t_min < near.0 && near.0 < t_max {
+                near
+            } else if t_min < far.0 && far.0 < t_max {
+                far
+            } else {
+                return None;
+            };
+            let p = r.at_t(t);
+            let mut normal = Vec3A::zero();
+            normal[axis] = if p[axis] - min[axis] < max[axis] - p[axis] { -1.0 } else { 1.0 };
+            // The position on the face, along the other two axes
+            let uv = (p - min) / (max - min);
+            let uv = Vec2::new(uv[(axis + 1) % 3], uv[(axis + 2) % 3]);
+            Some(HitRecord::new(t, p, normal, uv, None))
+        }
+    }
+}
+
+pub fn bounding_box(position: &Position, transform: Option<&Transform>, hitable: &Hitable) -> Aabb {
+    let transform = match transform {
+        Some(transform) => transform,
+        None => return object_bounds(position, hitable),
+    };
+    let bounds = object_bounds(&Position(Vec3A::zero()), hitable);
+    if bounds.is_empty() || !bounds.is_finite() {
+        return bounds;
+    }
+    // The transformed box's extent along each axis is the sum of the extents of its edges
+    let center = transform.to_world(bounds.centroid());
+    let half = 0.5 * bounds.extent();
+    let extent = transform.to_world(Vec3A::new(half.x(), 0.0, 0.0)).abs()
+        + transform.to_world(Vec3A::new(0.0, half.y(), 0.0)).abs()
+        + transform.to_world(Vec3A::new(0.0, 0.0, half.z())).abs();
+    Aabb::new(center - extent, center + extent).offset(position.0)
+}
+
+fn object_bounds(position: &Position, hitable: &Hitable) -> Aabb {
+    match hitable {
+        Hitable::ConstantMedium(h) => object_bounds(position, &h.boundary),
+        Hitable::HeterogeneousMedium(h) => object_bounds(position, &h.boundary),
+        Hitable::Sphere(h) => {
+            // Negative radii are used for hollow spheres
+            let r = Vec3A::splat(h.radius.abs());
+            Aabb::new(position.0 - r, position.0 + r)
+        }
+        Hitable::Triangle(h) => {
+            Aabb::empty().grow(h.v0).grow(h.v1).grow(h.v2).offset(position.0)
+        }
+        Hitable::Mesh(h) => h.bounds().offset(position.0),
+        Hitable::Group(h) => h.bounds().offset(position.0),
+        Hitable::Instance(h) => object_bounds(position, h),
+        Hitable::Plane(_) => Aabb::infinite(),
+        Hitable::Quad(h) => {
+            Aabb::empty().grow(Vec3A::zero()).grow(h.u).grow(h.v).grow(h.u + h.v)
+                .offset(position.0)
+                .pad(1e-4)
+        }
+        Hitable::Disk(h) => {
+            // The extent of a circle along each axis is its radius times the sine of the angle
+            // between the axis and the normal
+            let n = h.normal;
+            let sine = |c: f32| (1.0 - c * c).max(0.0).sqrt();
+            let extent = h.radius * Vec3A::new(sine(n.x()), sine(n.y()), sine(n.z()));
+            Aabb::new(position.0 - extent, position.0 + extent).pad(1e-4)
+        }
+        Hitable::Cuboid(h) => Aabb::new(h.min, h.max).offset(position.0),
+    }
+}
+
+/// Whether `sample_direction` supports the hitable, so that it can be sampled as a light.
+/// Transformed spheres are not, as they may no longer be round.
+pub fn is_samplable(hitable: &Hitable, transform: Option<&Transform>) -> bool {
+    match hitable {
+        Hitable::Sphere(_) => transform.is_none(),
+        Hitable::Quad(_) | Hitable::Disk(_) => true,
+        Hitable::ConstantMedium(_) | Hitable::Cuboid(_) | Hitable::Group(_) | Hitable::HeterogeneousMedium(_)
+            | Hitable::Instance(_) | Hitable::Mesh(_) | Hitable::Plane(_) | Hitable::Triangle(_) => false,
+    }
+}
+
+// The cosine of the half-angle of the cone that a sphere subtends as seen from a point outside of
+// it, as 1 - cos to keep precision for small or distant spheres
+fn sphere_cone(center: Vec3A, radius: f32, origin: Vec3A) -> Option<f32> {
+    let distance_squared = (center - origin).length_squared();
+    let sin2_theta_max = radius * radius / distance_squared;
+    if sin2_theta_max >= 1.0 {
+        return None;
+    }
+    let cos_theta_max = (1.0 - sin2_theta_max).sqrt();
+    Some(sin2_theta_max / (1.0 + cos_theta_max))
+}
+
+/// Sample a direction from `origin` towards the hitable given two uniform random numbers.
+/// Returns the unit direction and its solid angle probability density.
+pub fn sample_direction(position: &Position, transform: Option<&Transform>, hitable: &Hitable, origin: Vec3A, u: (f32, f32)) -> Option<(Vec3A, f32)> {
+    if !is_samplable(hitable, transform) {
+        return None;
+    }
+    match hitable {
+        Hitable::Sphere(h) => {
+            // Uniformly sample the cone of directions that the sphere subtends
+            let one_minus_cos_theta_max = sphere_cone(position.0, h.radius, origin)?;
+            let cos_theta = 1.0 - u.0 * one_minus_cos_theta_max;
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let phi = 2.0 * PI * u.1;
+            let w = (position.0 - origin).normalize();
+            let (u_axis, v_axis) = orthonormal_basis(w);
+            let direction = sin_theta * phi.cos() * u_axis + sin_theta * phi.sin() * v_axis + cos_theta * w;
+            Some((direction, 1.0 / (2.0 * PI * one_minus_cos_theta_max)))
+        }
+        Hitable::Quad(_) | Hitable::Disk(_) => {
+            // Uniformly sample the area, converting the density to solid angle
+            let area = area(hitable, transform)?;
+            let (normal, point) = area_sample(position, transform, hitable, u)?;
+            let to_point = point - origin;
+            let distance_squared = to_point.length_squared();
+            let direction = to_point / distance_squared.sqrt();
+            let cosine = normal.dot(direction).abs();
+            if cosine < 1e-6 {
+                return None;
+            }
+            Some((direction, distance_squared / (cosine * area)))
+        }
+        Hitable::ConstantMedium(_) | Hitable::Cuboid(_) | Hitable::Group(_) | Hitable::HeterogeneousMedium(_)
+            | Hitable::Instance(_) | Hitable::Mesh(_) | Hitable::Plane(_) | Hitable::Triangle(_) => None,
+    }
+}
+
+/// The solid angle probability density of `sample_direction` having sampled the direction from
+/// `origin` to the point described by `rec` on the hitable
+pub fn direction_pdf(position: &Position, transform: Option<&Transform>, hitable: &Hitable, origin: Vec3A, rec: &HitRecord) -> f32 {
+    if !is_samplable(hitable, transform) {
+        return 0.0;
+    }
+    match hitable {
+        Hitable::Sphere(h) => {
+            match sphere_cone(position.0, h.radius, origin) {
+                Some(one_minus_cos_theta_max) => 1.0 / (2.0 * PI * one_minus_cos_theta_max),
+                None => 0.0,
+            }
+        }
+        Hitable::Quad(_) | Hitable::Disk(_) => {
+            let area = area(hitable, transform).unwrap_or(0.0);
+            let to_point = rec.p - origin;
+            let distance_squared = to_point.length_squared();
+            let cosine = rec.normal.dot(to_point).abs() / distance_squared.sqrt();
+            if cosine < 1e-6 {
+                return 0.0;
+            }
+            distance_squared / (cosine * area)
+        }
+        Hitable::ConstantMedium(_) | Hitable::Cuboid(_) | Hitable::Group(_) | Hitable::HeterogeneousMedium(_)
+            | Hitable::Instance(_) | Hitable::Mesh(_) | Hitable::Plane(_) | Hitable::Triangle(_) => 0.0,
     }
 }
@@ -1,3 +1,5 @@
+#[macro_use]
+extern crate failure;
 extern crate glam;
 extern crate hibitset;
 extern crate image;
@@ -9,26 +11,48 @@ extern crate thread_local;
 pub use specs::prelude::*;
 pub use glam::*;
 
+mod aabb;
+mod blue_noise;
+mod bvh;
 mod camera;
 mod color;
 mod components;
+mod denoise;
 mod hitable;
 mod material;
+mod mesh;
+mod microfacet;
+mod obj;
+mod output;
 mod ray;
 mod resources;
+mod sampler;
+mod scene_file;
 mod scenes;
 mod systems;
+mod texture;
 mod timers;
 mod utils;
+mod volume;
 
+pub use aabb::*;
+pub use blue_noise::*;
+pub use bvh::*;
 pub use camera::*;
 pub use color::*;
 pub use components::*;
+pub use denoise::*;
 pub use hitable::*;
 pub use material::*;
+pub use mesh::*;
+pub use obj::*;
+pub use output::*;
 pub use ray::*;
 pub use resources::*;
+pub use sampler::*;
+pub use scene_file::*;
 pub use scenes::*;
 pub use systems::*;
+pub use texture::*;
 pub use timers::*;
 pub use utils::*;
@@ -3,18 +3,25 @@ extern crate clap;
 extern crate failure;
 extern crate partyarty;
 extern crate rand;
+#[cfg(feature = "preview")]
 extern crate sdl2;
 
 use clap::{App, Arg};
 use failure::Error;
 use partyarty::*;
-use rand::seq::SliceRandom;
-use rand::thread_rng;
+#[cfg(feature = "preview")]
 use sdl2::pixels::{Color, PixelFormatEnum};
+#[cfg(feature = "preview")]
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+#[cfg(feature = "preview")]
+use sdl2::keyboard::{Keycode, Scancode};
+#[cfg(feature = "preview")]
+use sdl2::mouse::MouseButton;
+#[cfg(feature = "preview")]
 use sdl2::rect::Rect;
 
+use std::time::Instant;
+
 
 const PKG_NAME: &'static str = env!("CARGO_PKG_NAME");
 const PKG_VERSION: &'static str = env!("CARGO_PKG_VERSION");
@@ -48,13 +55,13 @@ fn main() -> Result<(), Error> {
             .short("c")
             .long("scene")
             .value_name("SCENE")
-            .help("The scene to render")
+            .help("The built-in scene (balls, cornell, cornell_smoke, random, simple_light) or scene file to render")
             .takes_value(true))
         .arg(Arg::with_name("output")
             .short("o")
             .long("output")
             .value_name("OUTPUT")
-            .help("output image file name prefix")
+            .help("output image file name prefix. An .exr, .hdr, .pfm or .png extension selects the format.")
             .takes_value(true))
         .arg(Arg::with_name("framerate")
             .short("f")
@@ -62,49 +69,148 @@ fn main() -> Result<(), Error> {
             .value_name("FRAMERATE")
             .help("framerate of the preview")
             .takes_value(true))
+        .arg(Arg::with_name("converge")
+            .long("converge")
+            .value_name("THRESHOLD")
+            .help("Stop once the mean relative error of the pixels drops below this threshold")
+            .takes_value(true))
+        .arg(Arg::with_name("adaptive")
+            .long("adaptive")
+            .value_name("THRESHOLD")
+            .help("Sample the noisiest pixels first and stop sampling those whose relative error is below this threshold")
+            .takes_value(true))
+        .arg(Arg::with_name("sampler")
+            .long("sampler")
+            .value_name("SAMPLER")
+            .help("Where sample values come from")
+            .possible_values(&["independent", "blue_noise", "stratified", "halton", "sobol"])
+            .default_value("blue_noise")
+            .takes_value(true))
+        .arg(Arg::with_name("obj")
+            .long("obj")
+            .value_name("OBJ")
+            .help("Wavefront OBJ file to add to the scene")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("no-sky")
+            .long("no-sky")
+            .help("Turn off the sky so that the scene is only lit by emissive materials"))
+        .arg(Arg::with_name("headless")
+            .long("headless")
+            .help("Render without opening a preview window and write the final image"))
+        .arg(Arg::with_name("spp")
+            .long("spp")
+            .value_name("SPP")
+            .help("Samples per pixel at which pixels stop being sampled and a headless render stops")
+            .takes_value(true))
+        .arg(Arg::with_name("time")
+            .long("time")
+            .value_name("SECONDS")
+            .help("Time budget in seconds after which a headless render stops")
+            .takes_value(true))
+        .arg(Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .help("Output image format. PNG is the display image, the others are linear radiance.")
+            .possible_values(&["exr", "hdr", "pfm", "png"])
+            .takes_value(true))
+        .arg(Arg::with_name("exposure")
+            .long("exposure")
+            .value_name("STOPS")
+            .help("Exposure adjustment of the display image")
+            .default_value("0")
+            .takes_value(true))
+        .arg(Arg::with_name("tonemap")
+            .long("tonemap")
+            .value_name("OPERATOR")
+            .help("Tonemapping operator for the display image")
+            .possible_values(&["none", "reinhard", "aces", "agx"])
+            .default_value("none")
+            .takes_value(true))
+        .arg(Arg::with_name("dither")
+            .long("dither")
+            .help("Dither the display image to hide banding"))
+        .arg(Arg::with_name("denoise")
+            .long("denoise")
+            .help("Denoise the beauty image, guided by the albedo, normal and depth of each pixel"))
+        .arg(Arg::with_name("view")
+            .long("view")
+            .value_name("VIEW")
+            .help("The image shown in the preview")
+            .possible_values(&["beauty", "albedo", "normal", "depth", "id"])
+            .default_value("beauty")
+            .takes_value(true))
+        .arg(Arg::with_name("aovs")
+            .long("aovs")
+            .help("Also save the albedo, normal, depth and object id images next to each image"))
+        .arg(Arg::with_name("seed")
+            .long("seed")
+            .value_name("SEED")
+            .help("Seed for the random scene and the samples, chosen at random and printed when not given. The same seed, scene and samples per pixel give the same image.")
+            .takes_value(true))
         .get_matches();
 
-    let width: usize = value_t!(matches.value_of("width"), usize).unwrap_or(640);
-    let height: usize = value_t!(matches.value_of("height"), usize).unwrap_or(320);
-    let samples: usize = value_t!(matches.value_of("samples"), usize).unwrap_or(0);
-    let prefix: String = value_t!(matches.value_of("output"), String).unwrap_or(String::from(""));
-    let scene: String = value_t!(matches.value_of("scene"), String).unwrap_or(String::from("random"));
-    let framerate: f64 = value_t!(matches.value_of("framerate"), f64).unwrap_or(30.0f64);
-
-    let buffer_output: Vec<u8> = vec![0; width * height * 4];
+    let scene_name: String = value_t!(matches.value_of("scene"), String).unwrap_or(String::from("random"));
+    let seed: u64 = if matches.is_present("seed") {
+        value_t!(matches.value_of("seed"), u64).unwrap_or_else(|e| e.exit())
+    } else {
+        let seed = rand::random();
+        println!("Seed {}", seed);
+        seed
+    };
 
     let mut world = World::new();
     register_components(&mut world);
 
-    let camera;
-    let mut entities = match scene.as_ref() {
-        "balls" => {
-            let look_from = Vec3A::new(3.0, 3.0, 2.0);
-            let look_at = Vec3A::new(0.0, 0.0, -1.0);
-            camera = Camera::new(
-                look_from,
-                look_at,
-                Vec3A::new(0.0, 1.0, 0.0),
-                20.0,
-                width as f32 / height as f32,
-                2.0,
-                (look_from - look_at).length(),
-            );
-            balls(&mut world)
-        },
-        "random" | _ => {
-            camera = Camera::new(
-                Vec3A::new(13.0, 2.0, 3.0),
-                Vec3A::new(0.0, 0.0, 0.0),
-                Vec3A::new(0.0, 1.0, 0.0),
-                20.0,
-                width as f32 / height as f32,
-                0.1,
-                10.0,
-            );
-            random_scene(&mut world)
-        },
+    let scene = load_scene(&mut world, &scene_name, seed)?;
+    let mut entities = scene.entities;
+    if let Some(paths) = matches.values_of("obj") {
+        for path in paths {
+            entities.extend(load_obj(&mut world, path)?);
+        }
+    }
+    let render = scene.render;
+
+    let width: usize = value_t!(matches.value_of("width"), usize).ok().or(render.width).unwrap_or(640);
+    let height: usize = value_t!(matches.value_of("height"), usize).ok().or(render.height).unwrap_or(320);
+    let samples: usize = value_t!(matches.value_of("samples"), usize).ok().or(render.samples).unwrap_or(0);
+    let mut prefix: String = value_t!(matches.value_of("output"), String).unwrap_or(String::from(""));
+    let extension_format = ImageFormat::from_path(&prefix);
+    if let Some(format) = extension_format {
+        let stem_len = prefix.len() - format.extension().len() - 1;
+        prefix.truncate(stem_len);
+    }
+    let format = value_t!(matches.value_of("format"), ImageFormat).ok()
+        .or(extension_format)
+        .unwrap_or_default();
+    let framerate: f64 = value_t!(matches.value_of("framerate"), f64).ok().or(render.framerate).unwrap_or(30.0f64);
+    let spp: usize = value_t!(matches.value_of("spp"), usize).ok().or(render.spp).unwrap_or(0);
+    let time: f64 = value_t!(matches.value_of("time"), f64).unwrap_or(0.0f64);
+    let converge: f32 = value_t!(matches.value_of("converge"), f32).unwrap_or(0.0f32);
+    let mut adaptive = AdaptiveSampling::default();
+    if let Ok(threshold) = value_t!(matches.value_of("adaptive"), f32) {
+        adaptive.enabled = true;
+        adaptive.threshold = threshold;
+    }
+    let sampler = value_t!(matches.value_of("sampler"), SamplerKind).unwrap_or_default();
+    let display = DisplaySettings {
+        exposure: value_t!(matches.value_of("exposure"), f32).unwrap_or_else(|e| e.exit()),
+        tonemap: value_t!(matches.value_of("tonemap"), Tonemap).unwrap_or_default(),
+        dither: matches.is_present("dither"),
+        view: value_t!(matches.value_of("view"), View).unwrap_or_default(),
     };
+    let background = if matches.is_present("no-sky") {
+        Background::Color(Vec3A::zero())
+    } else {
+        render.background.unwrap_or_default()
+    };
+    let headless = matches.is_present("headless") || !cfg!(feature = "preview");
+
+    let buffer_output: Vec<u8> = vec![0; width * height * 4];
+
+    let camera = scene.camera.camera(width as f32 / height as f32);
+    let blue_noise = BlueNoise::generate(64, seed);
 
     {
         let mut coords = Vec::with_capacity(width * height);
@@ -113,7 +219,11 @@ fn main() -> Result<(), Error> {
                 coords.push((x, y));
             }
         }
-        coords.shuffle(&mut thread_rng());
+        // Visit pixels in the order of their blue noise ranks so that any prefix of the order,
+        // and so the pixels sampled in a frame, is spread evenly over the image. Tiles with equal
+        // ranks are ordered by the noise too.
+        let tile = blue_noise.size();
+        coords.sort_by_key(|&(x, y)| (blue_noise.rank(x, y), blue_noise.rank(x / tile, y / tile), x, y));
         let color = pixel_color(0.0, 0.0, 0.0, 0.0);
         let sample_count = SampleCount(0.0f32);
         for (x, y) in coords {
@@ -122,13 +232,19 @@ fn main() -> Result<(), Error> {
                     .with(pixel_position(x, y))
                     .with(color)
                     .with(sample_count)
+                    .with(PixelSecondMoment(0.0))
+                    .with(PixelError(f32::INFINITY))
+                    .with(PixelAovs::default())
                     .build()
             );
         }
     }
 
     world.insert(camera);
-    world.insert(ImageFilePrefix(prefix));
+    world.insert(scene.camera);
+    world.insert(ImageFilePrefix(prefix.clone()));
+    world.insert(ImageFileFormat(format));
+    world.insert(SaveAovs(matches.is_present("aovs")));
     world.insert(Width(width));
     world.insert(Height(height));
     world.insert(Samples(samples));
@@ -137,13 +253,122 @@ fn main() -> Result<(), Error> {
     world.insert(TargetFrameDuration(1.0f64 / framerate));
     world.insert(BufferOutput(buffer_output));
     world.insert(PixelsToProcess(BitSet::new()));
+    world.insert(SceneVersion(0));
+    world.insert(background);
+    world.insert(render.fog.unwrap_or_default());
+    world.insert(adaptive);
+    world.insert(blue_noise);
+    world.insert(sampler);
+    world.insert(SampleLimit(spp));
+    world.insert(display);
+    // Headless renders only denoise the final image
+    let denoise = matches.is_present("denoise");
+    world.insert(Denoise { enabled: denoise && !headless, ..Denoise::default() });
+    world.insert(Seed(seed));
 
 
     let mut dispatcher = DispatcherBuilder::new()
-        .with(PathTrace, "path_trace", &[])
-        .with(SaveImage, "save_image", &["path_trace"])
+        .with(BuildBvh::default(), "build_bvh", &[])
+        .with(BuildLights::default(), "build_lights", &[])
+        .with(ClearAccumulation::default(), "clear_accumulation", &[])
+        .with(PathTrace::default(), "path_trace", &["build_bvh", "build_lights", "clear_accumulation"])
+        .with(MeasureConvergence, "measure_convergence", &["path_trace"])
+        .with(RefreshDisplay::default(), "refresh_display", &["path_trace"])
+        .with(DenoiseImage, "denoise_image", &["refresh_display"])
+        .with(SaveImage, "save_image", &["denoise_image"])
         .build();
+    dispatcher.setup(&mut world);
+
+    let timers = Timers::default();
+    world.insert(PerfTimers(timers));
+
+    if headless {
+        let settings = HeadlessSettings { samples, spp, time, converge, denoise, prefix };
+        run_headless(&mut world, &mut dispatcher, settings)
+    } else {
+        run_preview(&mut world, &mut dispatcher, width, height, samples, converge)
+    }
+}
+
+struct HeadlessSettings {
+    samples: usize,
+    spp: usize,
+    time: f64,
+    converge: f32,
+    denoise: bool,
+    prefix: String,
+}
+
+fn run_headless(world: &mut World, dispatcher: &mut Dispatcher, settings: HeadlessSettings) -> Result<(), Error> {
+    let adaptive = world.read_resource::<AdaptiveSampling>().enabled;
+    if settings.samples == 0 && settings.spp == 0 && settings.time <= 0.0 && settings.converge <= 0.0 && !adaptive {
+        return Err(failure::err_msg("headless rendering needs --samples, --spp, --time, --converge or --adaptive to know when to stop"));
+    }
+
+    let start = Instant::now();
+    let mut samples_per_sec = SlidingAverage::default();
+    loop {
+        if settings.samples > 0 && world.read_resource::<FrameCount>().0 > settings.samples as u32 {
+            break;
+        }
+        if settings.spp > 0 {
+            let min_samples = world.read_storage::<SampleCount>()
+                .join()
+                .fold(f32::MAX, |acc, sample_count| acc.min(sample_count.0));
+            if min_samples >= settings.spp as f32 {
+                break;
+            }
+        }
+        if settings.time > 0.0 && start.elapsed().as_secs_f64() >= settings.time {
+            break;
+        }
+        if is_converged(world, settings.converge) {
+            break;
+        }
+        if adaptive && world.read_resource::<FrameCount>().0 > 0 && world.read_resource::<Convergence>().active_pixels == 0 {
+            break;
+        }
+
+        timer_enter(world, "frame");
+        timer_enter(world, "LOOP : dispatch");
+        dispatcher.dispatch(world);
+        world.maintain();
+        timer_exit(world, "LOOP : dispatch");
+        timer_exit(world, "frame");
+        timer_print(world);
+        print_samples_per_sec(world, &mut samples_per_sec);
+    }
 
+    if settings.denoise {
+        world.write_resource::<Denoise>().enabled = true;
+        DenoiseImage.run_now(world);
+    }
+
+    let prefix = if settings.prefix.is_empty() { "render" } else { &settings.prefix };
+    let filenames = save_views(
+        prefix,
+        world.read_resource::<ImageFileFormat>().0,
+        world.read_resource::<SaveAovs>().0,
+        &world.read_resource::<DisplaySettings>(),
+        &world.read_resource::<BlueNoise>(),
+        world.read_resource::<Width>().0,
+        world.read_resource::<Height>().0,
+        &world.system_data::<PixelData>(),
+        &world.read_resource::<DenoisedImage>().0,
+    )?;
+    for filename in filenames {
+        println!("Wrote {}", filename);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "preview"))]
+fn run_preview(_: &mut World, _: &mut Dispatcher, _: usize, _: usize, _: usize, _: f32) -> Result<(), Error> {
+    unreachable!("built without the preview feature")
+}
+
+#[cfg(feature = "preview")]
+fn run_preview(world: &mut World, dispatcher: &mut Dispatcher, width: usize, height: usize, samples: usize, converge: f32) -> Result<(), Error> {
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
 
@@ -165,21 +390,44 @@ fn main() -> Result<(), Error> {
     let mut event_pump = sdl_context.event_pump().unwrap();
 
     let mut samples_per_sec = SlidingAverage::default();
-    let timers = Timers::default();
-    world.insert(PerfTimers(timers));
+    let mut controller = CameraController::default();
+    let mut last_frame = Instant::now();
+    println!("Camera controls: W/A/S/D/Q/E to fly (shift for faster), hold the left mouse button to look around, \
+        scroll to zoom, [ and ] for aperture, - and = for focus distance");
+    println!("Display controls: , and . for exposure, T to cycle tonemapping operators, V to cycle views, N to toggle denoising");
 
     'mainloop: loop {
-        timer_enter(&mut world, "frame");
-        timer_enter(&mut world, "LOOP : events");
+        timer_enter(world, "frame");
+        timer_enter(world, "LOOP : events");
+        let mut params = *world.read_resource::<CameraParams>();
+        let mut camera_changed = false;
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit {..} |
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                     break 'mainloop
                 },
-                _ => {}
+                Event::KeyDown { keycode: Some(keycode), .. } if adjust_display(world, keycode) => {},
+                _ => {
+                    if controller.handle_event(&event, &mut params) {
+                        camera_changed = true;
+                    }
+                    if let Event::MouseButtonDown { mouse_btn: MouseButton::Left, .. } |
+                        Event::MouseButtonUp { mouse_btn: MouseButton::Left, .. } = event {
+                        sdl_context.mouse().set_relative_mouse_mode(controller.mouse_look);
+                    }
+                }
             }
         }
+        let dt = last_frame.elapsed().as_secs_f32();
+        last_frame = Instant::now();
+        if controller.fly(&event_pump.keyboard_state(), dt, &mut params) {
+            camera_changed = true;
+        }
+        if camera_changed {
+            world.insert(params.camera(width as f32 / height as f32));
+            world.insert(params);
+        }
 
         if samples > 0 {
             let frame_count = world.read_resource::<FrameCount>();
@@ -187,12 +435,15 @@ fn main() -> Result<(), Error> {
                 break;
             }
         }
+        if is_converged(world, converge) {
+            break;
+        }
 
-        timer_transition(&mut world, "LOOP : events", "LOOP : dispatch");
-        dispatcher.dispatch(&mut world);
+        timer_transition(world, "LOOP : events", "LOOP : dispatch");
+        dispatcher.dispatch(world);
         world.maintain();
 
-        timer_transition(&mut world, "LOOP : dispatch", "LOOP : update_frame");
+        timer_transition(world, "LOOP : dispatch", "LOOP : update_frame");
 
         {
             world.exec(|(buffer, ): (Read<BufferOutput>, )| {
@@ -200,30 +451,146 @@ fn main() -> Result<(), Error> {
             });
         }
         canvas.copy(&texture, rect, rect).unwrap();
-        timer_exit(&mut world, "LOOP : update_frame");
+        timer_exit(world, "LOOP : update_frame");
 
         canvas.present();
 
-        timer_exit(&mut world, "frame");
-        timer_print(&mut world);
-        let mut samples_per_sec_for_frame = 0.0;
-        {
-            world.exec(|(timers, samples_to_process,): (Read<PerfTimers>, Read<SamplesToProcessPerFrame>,)| {
-                samples_per_sec_for_frame = samples_to_process.0 as f64 * 1000.0 / timers.0.frames_mean.q.back().unwrap();
-            });
-        }
-        let mean = samples_per_sec.append(samples_per_sec_for_frame);
-        {
-            let frame_count = world.read_resource::<FrameCount>().0;
-            if frame_count % 10 == 0 {
-                println!(
-                    "\tmean: {:.3} Msamples/s, frame: {:.3} Msamples/s",
-                    mean / 1_000_000f64,
-                    samples_per_sec_for_frame / 1_000_000f64,
-                );
+        timer_exit(world, "frame");
+        timer_print(world);
+        print_samples_per_sec(world, &mut samples_per_sec);
+    }
+
+    Ok(())
+}
+
+/// Handles the display setting keys. Returns whether the key was one of them.
+#[cfg(feature = "preview")]
+fn adjust_display(world: &mut World, keycode: Keycode) -> bool {
+    if keycode == Keycode::N {
+        let mut denoise = world.write_resource::<Denoise>();
+        denoise.enabled = !denoise.enabled;
+        println!("Denoising {}", if denoise.enabled { "on" } else { "off" });
+        return true;
+    }
+    let mut display = world.write_resource::<DisplaySettings>();
+    match keycode {
+        Keycode::Comma => display.exposure -= 0.5,
+        Keycode::Period => display.exposure += 0.5,
+        Keycode::T => display.tonemap = display.tonemap.next(),
+        Keycode::V => display.view = display.view.next(),
+        _ => return false,
+    }
+    println!(
+        "Display: {}, exposure {:+.1}, tonemapping {:?}",
+        display.view.name(),
+        display.exposure,
+        display.tonemap,
+    );
+    true
+}
+
+fn is_converged(world: &World, threshold: f32) -> bool {
+    threshold > 0.0 && world.read_resource::<Convergence>().relative_error < threshold
+}
+
+fn print_samples_per_sec(world: &mut World, samples_per_sec: &mut SlidingAverage) {
+    let mut samples_per_sec_for_frame = 0.0;
+    {
+        world.exec(|(timers, samples_to_process,): (Read<PerfTimers>, Read<SamplesToProcessPerFrame>,)| {
+            samples_per_sec_for_frame = samples_to_process.0 as f64 * 1000.0 / timers.0.frames_mean.q.back().unwrap();
+        });
+    }
+    let mean = samples_per_sec.append(samples_per_sec_for_frame);
+    {
+        let frame_count = world.read_resource::<FrameCount>().0;
+        if frame_count % 10 == 0 {
+            let convergence = world.read_resource::<Convergence>();
+            println!(
+                "\tmean: {:.3} Msamples/s, frame: {:.3} Msamples/s, relative error: {:.5}, variance: {:.3e}",
+                mean / 1_000_000f64,
+                samples_per_sec_for_frame / 1_000_000f64,
+                convergence.relative_error,
+                convergence.variance,
+            );
+            if world.read_resource::<AdaptiveSampling>().enabled {
+                println!("\tactive pixels: {}", convergence.active_pixels);
             }
         }
     }
+}
 
-    Ok(())
+#[cfg(feature = "preview")]
+struct CameraController {
+    mouse_look: bool,
+    // Distance units per second
+    speed: f32,
+    // Radians per pixel of mouse motion
+    sensitivity: f32,
+}
+
+#[cfg(feature = "preview")]
+impl Default for CameraController {
+    fn default() -> CameraController {
+        CameraController {
+            mouse_look: false,
+            speed: 2.0,
+            sensitivity: 0.003,
+        }
+    }
+}
+
+#[cfg(feature = "preview")]
+impl CameraController {
+    /// Returns whether the camera parameters were changed.
+    fn handle_event(&mut self, event: &Event, params: &mut CameraParams) -> bool {
+        match *event {
+            Event::MouseButtonDown { mouse_btn: MouseButton::Left, .. } => {
+                self.mouse_look = true;
+                false
+            },
+            Event::MouseButtonUp { mouse_btn: MouseButton::Left, .. } => {
+                self.mouse_look = false;
+                false
+            },
+            Event::MouseMotion { xrel, yrel, .. } if self.mouse_look => {
+                params.look(-xrel as f32 * self.sensitivity, -yrel as f32 * self.sensitivity);
+                true
+            },
+            Event::MouseWheel { y, .. } if y != 0 => {
+                params.zoom(0.9f32.powi(y));
+                true
+            },
+            Event::KeyDown { keycode: Some(keycode), .. } => {
+                match keycode {
+                    Keycode::LeftBracket => params.aperture = (params.aperture - 0.05).max(0.0),
+                    Keycode::RightBracket => params.aperture += 0.05,
+                    Keycode::Minus => params.focus_dist = (params.focus_dist / 1.1).max(0.01),
+                    Keycode::Equals => params.focus_dist *= 1.1,
+                    _ => return false,
+                }
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Moves the camera according to the held movement keys. Returns whether it moved.
+    fn fly(&self, keyboard: &sdl2::keyboard::KeyboardState, dt: f32, params: &mut CameraParams) -> bool {
+        let axis = |positive: Scancode, negative: Scancode| {
+            keyboard.is_scancode_pressed(positive) as i32 as f32
+                - keyboard.is_scancode_pressed(negative) as i32 as f32
+        };
+        let forward = axis(Scancode::W, Scancode::S);
+        let right = axis(Scancode::D, Scancode::A);
+        let up = axis(Scancode::E, Scancode::Q);
+        if forward == 0.0 && right == 0.0 && up == 0.0 {
+            return false;
+        }
+        let mut distance = self.speed * dt;
+        if keyboard.is_scancode_pressed(Scancode::LShift) {
+            distance *= 5.0;
+        }
+        params.fly(forward * distance, right * distance, up * distance);
+        true
+    }
 }
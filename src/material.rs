@@ -1,8 +1,14 @@
 use glam::Vec3A;
 
 use hitable::HitRecord;
+use microfacet;
+use microfacet::{fresnel_conductor, fresnel_dielectric, Frame, Ggx};
 use ray::Ray;
-use utils::{random_float_01, random_in_unit_sphere};
+use sampler::Sampler;
+use texture::{Texture, Textures};
+use utils::{orthonormal_basis, sample_unit_ball, sample_unit_vector};
+
+use std::f32::consts::PI;
 
 fn reflect(v: &Vec3A, n: &Vec3A) -> Vec3A {
     *v - 2.0 * v.dot(*n) * *n
@@ -24,23 +30,46 @@ fn schlick(cosine: f32, ref_idx: f32) -> f32 {
     r1 + (1.0 - r1) * (1.0 - cosine).powf(5.0)
 }
 
+// The Henyey-Greenstein phase function for the cosine of the angle between the direction of
+// travel and the scattered direction. Positive `g` scatters forwards, zero in every direction
+// alike and negative backwards.
+fn henyey_greenstein_phase(cosine: f32, g: f32) -> f32 {
+    let denominator = 1.0 + g * g - 2.0 * g * cosine;
+    (1.0 - g * g) / (4.0 * PI * denominator * denominator.max(1e-12).sqrt())
+}
+
+// A unit direction distributed by the Henyey-Greenstein phase function about the unit direction
+// of travel `w`
+fn sample_henyey_greenstein(w: Vec3A, g: f32, u: (f32, f32)) -> Vec3A {
+    let cosine = if g.abs() < 1e-3 {
+        1.0 - 2.0 * u.0
+    } else {
+        let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u.0);
+        ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+    };
+    let sine = (1.0 - cosine * cosine).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    let (a, b) = orthonormal_basis(w);
+    sine * phi.cos() * a + sine * phi.sin() * b + cosine * w
+}
+
 #[derive(Clone, Copy)]
 pub struct Lambertian {
-    pub albedo: Vec3A,
+    pub albedo: Texture,
 }
 
-pub fn lambertian(albedo: Vec3A) -> Material {
-    Material::Lambertian(Lambertian { albedo })
+pub fn lambertian<T: Into<Texture>>(albedo: T) -> Material {
+    Material::Lambertian(Lambertian { albedo: albedo.into() })
 }
 
 #[derive(Clone, Copy)]
 pub struct Metal{
-    pub albedo: Vec3A,
+    pub albedo: Texture,
     pub fuzz: f32,
 }
 
-pub fn metal(albedo: Vec3A, fuzz: f32) -> Material {
-    Material::Metal(Metal { albedo, fuzz })
+pub fn metal<T: Into<Texture>>(albedo: T, fuzz: f32) -> Material {
+    Material::Metal(Metal { albedo: albedo.into(), fuzz })
 }
 
 #[derive(Clone, Copy)]
@@ -52,70 +81,321 @@ pub fn dielectric(ref_idx: f32) -> Material {
     Material::Dielectric(Dielectric { ref_idx })
 }
 
+/// A metal with GGX distributed microfacets of the given `roughness`, in [0, 1], reflecting by
+/// the Fresnel equations for its complex index of refraction `eta` + i`k` in each channel
+#[derive(Clone, Copy)]
+pub struct Conductor {
+    pub eta: Vec3A,
+    pub k: Vec3A,
+    pub roughness: f32,
+}
+
+pub fn conductor(eta: Vec3A, k: Vec3A, roughness: f32) -> Material {
+    Material::Conductor(Conductor { eta, k, roughness })
+}
+
+/// The complex index of refraction of a few metals at red, green and blue wavelengths
+pub fn named_conductor(name: &str) -> Option<(Vec3A, Vec3A)> {
+    match name {
+        "aluminium" => Some((Vec3A::new(1.657, 0.880, 0.521), Vec3A::new(9.224, 6.270, 4.837))),
+        "copper" => Some((Vec3A::new(0.200, 0.924, 1.102), Vec3A::new(3.912, 2.452, 2.142))),
+        "gold" => Some((Vec3A::new(0.143, 0.374, 1.442), Vec3A::new(3.983, 2.385, 1.603))),
+        "silver" => Some((Vec3A::new(0.155, 0.117, 0.138), Vec3A::new(4.828, 3.122, 2.147))),
+        _ => None,
+    }
+}
+
+/// Glass with GGX distributed microfacets of the given `roughness`, each of which reflects or
+/// refracts light like a smooth `Dielectric`
+#[derive(Clone, Copy)]
+pub struct RoughDielectric {
+    pub ref_idx: f32,
+    pub roughness: f32,
+}
+
+pub fn rough_dielectric(ref_idx: f32, roughness: f32) -> Material {
+    Material::RoughDielectric(RoughDielectric { ref_idx, roughness })
+}
+
+#[derive(Clone, Copy)]
+pub struct DiffuseLight {
+    pub emit: Vec3A,
+}
+
+pub fn diffuse_light(emit: Vec3A) -> Material {
+    Material::DiffuseLight(DiffuseLight { emit })
+}
+
+/// The scattering of a participating medium, a fraction `albedo` of the light that collides with
+/// it being scattered by the Henyey-Greenstein phase function with asymmetry `g`
+#[derive(Clone, Copy)]
+pub struct Volume {
+    pub albedo: Texture,
+    pub g: f32,
+}
+
+pub fn isotropic<T: Into<Texture>>(albedo: T) -> Material {
+    henyey_greenstein(albedo, 0.0)
+}
+
+pub fn henyey_greenstein<T: Into<Texture>>(albedo: T, g: f32) -> Material {
+    Material::Volume(Volume { albedo: albedo.into(), g: g.clamp(-0.99, 0.99) })
+}
+
 #[derive(Clone, Copy)]
 pub enum Material {
+    Conductor(Conductor),
     Dielectric(Dielectric),
+    DiffuseLight(DiffuseLight),
     Lambertian(Lambertian),
     Metal(Metal),
+    RoughDielectric(RoughDielectric),
+    Volume(Volume),
 }
 
-pub fn scatter(r_in: &Ray, rec: &HitRecord) -> Option<(Vec3A, Ray)> {
+pub fn emitted(rec: &HitRecord) -> Vec3A {
+    match rec.material {
+        Some(Material::DiffuseLight(m)) => m.emit,
+        _ => Vec3A::zero(),
+    }
+}
+
+/// The reflectance of the surface, as a guide for denoising and for display
+pub fn albedo(rec: &HitRecord, textures: &Textures) -> Vec3A {
+    match rec.material {
+        Some(Material::Conductor(m)) => fresnel_conductor(1.0, m.eta, m.k),
+        Some(Material::Dielectric(_)) | Some(Material::RoughDielectric(_)) => Vec3A::one(),
+        Some(Material::DiffuseLight(m)) => m.emit.min(Vec3A::one()),
+        Some(Material::Lambertian(m)) => textures.value(&m.albedo, rec.uv, rec.p),
+        Some(Material::Metal(m)) => textures.value(&m.albedo, rec.uv, rec.p),
+        Some(Material::Volume(m)) => textures.value(&m.albedo, rec.uv, rec.p),
+        None => Vec3A::zero(),
+    }
+}
+
+pub struct ScatterRecord {
+    // The path throughput weight of the scattered ray, the BSDF times cosine divided by the pdf
+    pub attenuation: Vec3A,
+    pub scattered: Ray,
+    // Solid angle pdf of the scattered direction, None for specular scattering which cannot be
+    // combined with light sampling
+    pub pdf: Option<f32>,
+}
+
+impl ScatterRecord {
+    fn specular(attenuation: Vec3A, scattered: Ray) -> ScatterRecord {
+        ScatterRecord { attenuation, scattered, pdf: None }
+    }
+}
+
+// The shading frame of the side of the surface that the ray arrives from, the direction back
+// along the ray in that frame, and whether the ray arrives from the front of the surface
+fn facing_frame(r_in: &Ray, rec: &HitRecord) -> (Frame, Vec3A, bool) {
+    let frame = Frame::new(rec.normal);
+    let wo = frame.to_local(-r_in.direction.normalize());
+    (frame, wo, rec.front_face)
+}
+
+// The ratio of the index of refraction across a dielectric to that on the side the ray is on
+fn relative_eta(ref_idx: f32, front: bool) -> f32 {
+    if front { ref_idx } else { 1.0 / ref_idx }
+}
+
+// The factor relating the density of refracted directions to that of the microfacet normals that
+// refract `wo` into them
+fn refraction_jacobian(wo: Vec3A, wi: Vec3A, m: Vec3A, eta: f32) -> f32 {
+    let denominator = wo.dot(m) + eta * wi.dot(m);
+    eta * eta * wi.dot(m).abs() / (denominator * denominator)
+}
+
+pub fn scatter(r_in: &Ray, rec: &HitRecord, textures: &Textures, sampler: &mut Sampler) -> Option<ScatterRecord> {
     if let Some(material) = rec.material {
         match material {
             Material::Lambertian(m) => {
-                let target = rec.p + rec.normal + random_in_unit_sphere();
-                let scattered = Ray::new(rec.p, target - rec.p);
-                return Some((m.albedo, scattered));
+                // Cosine-weighted hemisphere sampling
+                let mut direction = rec.normal + sample_unit_vector(sampler.next_2d());
+                if direction.length_squared() < 1e-8 {
+                    direction = rec.normal;
+                }
+                let scattered = Ray::new(rec.p, direction, r_in.time);
+                let pdf = direction.normalize().dot(rec.normal).max(0.0) / PI;
+                let attenuation = textures.value(&m.albedo, rec.uv, rec.p);
+                return Some(ScatterRecord { attenuation, scattered, pdf: Some(pdf) });
             },
             Material::Metal(m) => {
                 let reflected = reflect(&r_in.direction.normalize(), &rec.normal);
-                let scattered = Ray::new(rec.p, reflected + m.fuzz * random_in_unit_sphere());
+                let fuzz = sample_unit_ball(sampler.next_2d(), sampler.next_1d());
+                let scattered = Ray::new(rec.p, reflected + m.fuzz * fuzz, r_in.time);
                 if scattered.direction.dot(rec.normal) > 0.0 {
-                    return Some((m.albedo, scattered));
+                    return Some(ScatterRecord::specular(textures.value(&m.albedo, rec.uv, rec.p), scattered));
                 } else {
                     return None;
                 }
             },
             Material::Dielectric(m) => {
-                let outward_normal;
+                // The normal faces the ray, so rays that hit the back of the surface are leaving
                 let ni_over_nt;
                 let cosine;
 
-                let rdn = r_in.direction.dot(rec.normal);
-                if rdn > 0.0 {
-                    outward_normal = -rec.normal;
+                let rdn = -r_in.direction.dot(rec.normal);
+                if rec.front_face {
+                    ni_over_nt = 1.0 / m.ref_idx;
+                    cosine = rdn / r_in.direction.length();
+                } else {
                     ni_over_nt = m.ref_idx;
                     cosine = m.ref_idx * rdn / r_in.direction.length();
-                } else {
-                    outward_normal = rec.normal;
-                    ni_over_nt = 1.0 / m.ref_idx;
-                    cosine = -rdn / r_in.direction.length();
                 }
 
                 let attenuation = Vec3A::one();
-                match refract(&r_in.direction, &outward_normal, ni_over_nt) {
+                match refract(&r_in.direction, &rec.normal, ni_over_nt) {
                     Some(refracted) => {
-                        if schlick(cosine, m.ref_idx) > random_float_01() {
-                            return Some((
+                        if schlick(cosine, m.ref_idx) > sampler.next_1d() {
+                            return Some(ScatterRecord::specular(
                                 attenuation,
-                                Ray::new(rec.p, reflect(&r_in.direction, &rec.normal))
+                                Ray::new(rec.p, reflect(&r_in.direction, &rec.normal), r_in.time)
                             ));
                         } else {
-                            return Some((
+                            return Some(ScatterRecord::specular(
                                 attenuation,
-                                Ray::new(rec.p, refracted)
+                                Ray::new(rec.p, refracted, r_in.time)
                             ));
                         }
                     },
                     None => {
-                        return Some((
+                        return Some(ScatterRecord::specular(
                             attenuation,
-                            Ray::new(rec.p, reflect(&r_in.direction, &rec.normal))
+                            Ray::new(rec.p, reflect(&r_in.direction, &rec.normal), r_in.time)
                         ));
                     }
                 }
             },
+            Material::Conductor(m) => {
+                let (frame, wo, _) = facing_frame(r_in, rec);
+                let ggx = Ggx::from_roughness(m.roughness);
+                if ggx.is_smooth() {
+                    let wi = Vec3A::new(-wo.x(), -wo.y(), wo.z());
+                    let scattered = Ray::new(rec.p, frame.to_world(wi), r_in.time);
+                    return Some(ScatterRecord::specular(fresnel_conductor(wo.z(), m.eta, m.k), scattered));
+                }
+                if wo.z() <= 0.0 {
+                    return None;
+                }
+                // Visible normal sampling leaves only the Fresnel term and the masking of the
+                // scattered direction as the weight
+                let normal = ggx.sample_visible_normal(wo, sampler.next_2d());
+                let wi = microfacet::reflect(wo, normal);
+                if wi.z() <= 0.0 {
+                    return None;
+                }
+                let pdf = ggx.visible_pdf(wo, normal) / (4.0 * wo.dot(normal));
+                let attenuation = fresnel_conductor(wo.dot(normal), m.eta, m.k) * (ggx.g2(wo, wi) / ggx.g1(wo));
+                let scattered = Ray::new(rec.p, frame.to_world(wi), r_in.time);
+                return Some(ScatterRecord { attenuation, scattered, pdf: Some(pdf) });
+            },
+            Material::RoughDielectric(m) => {
+                let (frame, wo, front) = facing_frame(r_in, rec);
+                let eta = relative_eta(m.ref_idx, front);
+                let ggx = Ggx::from_roughness(m.roughness);
+                if wo.z() <= 0.0 {
+                    return None;
+                }
+                let normal = if ggx.is_smooth() {
+                    Vec3A::new(0.0, 0.0, 1.0)
+                } else {
+                    ggx.sample_visible_normal(wo, sampler.next_2d())
+                };
+                // Reflection or refraction is chosen by the Fresnel term, which then cancels
+                let fresnel = fresnel_dielectric(wo.dot(normal), eta);
+                let refracted = if sampler.next_1d() < fresnel { None } else { microfacet::refract(wo, normal, eta) };
+                let wi = refracted.unwrap_or_else(|| microfacet::reflect(wo, normal));
+                let scattered = Ray::new(rec.p, frame.to_world(wi), r_in.time);
+                if ggx.is_smooth() {
+                    return Some(ScatterRecord::specular(Vec3A::one(), scattered));
+                }
+                let pdf = match refracted {
+                    Some(_) if wi.z() < 0.0 => {
+                        (1.0 - fresnel) * ggx.visible_pdf(wo, normal) * refraction_jacobian(wo, wi, normal, eta)
+                    },
+                    None if wi.z() > 0.0 => fresnel * ggx.visible_pdf(wo, normal) / (4.0 * wo.dot(normal)),
+                    _ => return None,
+                };
+                let attenuation = Vec3A::splat(ggx.g2(wo, wi) / ggx.g1(wo));
+                return Some(ScatterRecord { attenuation, scattered, pdf: Some(pdf) });
+            },
+            Material::Volume(m) => {
+                // The phase function is sampled exactly, leaving only the albedo as the weight
+                let w = r_in.direction.normalize();
+                let direction = sample_henyey_greenstein(w, m.g, sampler.next_2d());
+                let pdf = henyey_greenstein_phase(direction.dot(w), m.g);
+                let scattered = Ray::new(rec.p, direction, r_in.time);
+                let attenuation = textures.value(&m.albedo, rec.uv, rec.p);
+                return Some(ScatterRecord { attenuation, scattered, pdf: Some(pdf) });
+            },
+            Material::DiffuseLight(_) => {},
         }
     }
     None
 }
+
+/// Evaluate the material for light arriving from the unit direction `wi`, returning `(f, pdf)`:
+/// `f` is the BSDF times cosine, or for volumes the phase function, and `pdf` is the solid angle
+/// density with which `scatter` samples `wi`, the same density it reports in `ScatterRecord::pdf`.
+/// Both come from one evaluation as the microfacet materials share most of the work between them.
+/// Returns None for materials with specular scattering.
+pub fn eval(r_in: &Ray, rec: &HitRecord, textures: &Textures, wi: Vec3A) -> Option<(Vec3A, f32)> {
+    match rec.material? {
+        Material::Lambertian(m) => {
+            let cosine = wi.dot(rec.normal).max(0.0);
+            Some((textures.value(&m.albedo, rec.uv, rec.p) * (cosine / PI), cosine / PI))
+        },
+        Material::Volume(m) => {
+            let phase = henyey_greenstein_phase(wi.dot(r_in.direction.normalize()), m.g);
+            Some((textures.value(&m.albedo, rec.uv, rec.p) * phase, phase))
+        },
+        Material::Conductor(m) => {
+            let ggx = Ggx::from_roughness(m.roughness);
+            if ggx.is_smooth() {
+                return None;
+            }
+            let (frame, wo, _) = facing_frame(r_in, rec);
+            let wi = frame.to_local(wi);
+            if wo.z() <= 0.0 || wi.z() <= 0.0 {
+                return Some((Vec3A::zero(), 0.0));
+            }
+            let h = (wo + wi).normalize();
+            let f_cos = fresnel_conductor(wo.dot(h), m.eta, m.k) * (ggx.d(h) * ggx.g2(wo, wi) / (4.0 * wo.z()));
+            Some((f_cos, ggx.visible_pdf(wo, h) / (4.0 * wo.dot(h))))
+        },
+        Material::RoughDielectric(m) => {
+            let ggx = Ggx::from_roughness(m.roughness);
+            if ggx.is_smooth() {
+                return None;
+            }
+            let (frame, wo, front) = facing_frame(r_in, rec);
+            let eta = relative_eta(m.ref_idx, front);
+            let wi = frame.to_local(wi);
+            if wo.z() <= 0.0 || wi.z() == 0.0 {
+                return Some((Vec3A::zero(), 0.0));
+            }
+            if wi.z() > 0.0 {
+                let h = (wo + wi).normalize();
+                let fresnel = fresnel_dielectric(wo.dot(h), eta);
+                let f_cos = fresnel * ggx.d(h) * ggx.g2(wo, wi) / (4.0 * wo.z());
+                return Some((Vec3A::splat(f_cos), fresnel * ggx.visible_pdf(wo, h) / (4.0 * wo.dot(h))));
+            }
+            // The microfacet normal that refracts `wo` into `wi`
+            let mut h = (wo + eta * wi).normalize();
+            if h.z() < 0.0 {
+                h = -h;
+            }
+            if wo.dot(h) <= 0.0 || wi.dot(h) >= 0.0 {
+                return Some((Vec3A::zero(), 0.0));
+            }
+            let transmission = 1.0 - fresnel_dielectric(wo.dot(h), eta);
+            let jacobian = refraction_jacobian(wo, wi, h, eta);
+            let f_cos = transmission * ggx.d(h) * ggx.g2(wo, wi) * wo.dot(h) * jacobian / wo.z();
+            Some((Vec3A::splat(f_cos), transmission * ggx.visible_pdf(wo, h) * jacobian))
+        },
+        Material::Metal(_) | Material::Dielectric(_) | Material::DiffuseLight(_) => None,
+    }
+}
@@ -0,0 +1,153 @@
+use glam::{Vec2, Vec3A};
+
+use aabb::Aabb;
+use bvh::Bvh;
+use hitable::HitRecord;
+use ray::Ray;
+
+/// Möller–Trumbore ray-triangle intersection. Returns the distance along the ray and the
+/// barycentric coordinates of the hit with respect to `v1` and `v2`.
+pub fn intersect_triangle(r: &Ray, v0: Vec3A, v1: Vec3A, v2: Vec3A, t_min: f32, t_max: f32) -> Option<(f32, f32, f32)> {
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+    let p = r.direction.cross(edge2);
+    let determinant = edge1.dot(p);
+    if determinant.abs() < 1e-12 {
+        return None;
+    }
+    let inv_determinant = 1.0 / determinant;
+    let s = r.origin - v0;
+    let b1 = s.dot(p) * inv_determinant;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+    let q = s.cross(edge1);
+    let b2 = r.direction.dot(q) * inv_determinant;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+    let t = edge2.dot(q) * inv_determinant;
+    if t_min < t && t < t_max {
+        Some((t, b1, b2))
+    } else {
+        None
+    }
+}
+
+/// An indexed triangle mesh with optional per-vertex normals and texture coordinates. Triangles
+/// are counter-clockwise when seen from the front. The mesh has its own bounding volume hierarchy
+/// over its triangles so that it is a single, cheap to intersect, entity in the scene.
+#[derive(Debug)]
+pub struct Mesh {
+    pub vertices: Vec<Vec3A>,
+    // Either empty or one per vertex
+    pub normals: Vec<Vec3A>,
+    // Either empty or one per vertex
+    pub uvs: Vec<Vec2>,
+    pub indices: Vec<[u32; 3]>,
+    bvh: Bvh<u32>,
+}
+
+impl Mesh {
+    pub fn new(vertices: Vec<Vec3A>, normals: Vec<Vec3A>, uvs: Vec<Vec2>, indices: Vec<[u32; 3]>) -> Mesh {
+        let triangles = indices.iter()
+            .enumerate()
+            .map(|(i, triangle)| {
+                let bounds = triangle.iter()
+                    .fold(Aabb::empty(), |acc, &v| acc.grow(vertices[v as usize]));
+                (bounds, i as u32)
+            })
+            .collect();
+        let bvh = Bvh::build(triangles);
+        Mesh { vertices, normals, uvs, indices, bvh }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.bvh.bounds()
+    }
+
+    pub fn triangle(&self, i: usize) -> (Vec3A, Vec3A, Vec3A) {
+        let [i0, i1, i2] = self.indices[i];
+        (self.vertices[i0 as usize], self.vertices[i1 as usize], self.vertices[i2 as usize])
+    }
+
+    /// Intersect a ray given in the mesh's own coordinate space
+    pub fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let mut closest = None;
+        self.bvh.traverse(r, t_min, t_max, |&i, t_max| {
+            let (v0, v1, v2) = self.triangle(i as usize);
+            let (t, b1, b2) = intersect_triangle(r, v0, v1, v2, t_min, t_max)?;
+            closest = Some((i as usize, b1, b2));
+            Some(t)
+        }).map(|t| {
+            let (i, b1, b2) = closest.unwrap();
+            let (v0, v1, v2) = self.triangle(i);
+            let [i0, i1, i2] = self.indices[i];
+            let b0 = 1.0 - b1 - b2;
+            let normal = if self.normals.is_empty() {
+                (v1 - v0).cross(v2 - v0).normalize()
+            } else {
+                (b0 * self.normals[i0 as usize]
+                    + b1 * self.normals[i1 as usize]
+                    + b2 * self.normals[i2 as usize]).normalize()
+            };
+            // Without texture coordinates, use the barycentric coordinates within the triangle
+            let uv = if self.uvs.is_empty() {
+                Vec2::new(b1, b2)
+            } else {
+                b0 * self.uvs[i0 as usize] + b1 * self.uvs[i1 as usize] + b2 * self.uvs[i2 as usize]
+            };
+            HitRecord::new(t, r.at_t(t), normal, uv, None)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use components::position;
+    use hitable::{hit, mesh};
+
+    fn unit_triangle() -> (Vec3A, Vec3A, Vec3A) {
+        (Vec3A::zero(), Vec3A::unit_x(), Vec3A::unit_y())
+    }
+
+    #[test]
+    fn triangle_hit_gives_distance_and_barycentrics() {
+        let (v0, v1, v2) = unit_triangle();
+        let r = Ray::new(Vec3A::new(0.25, 0.5, 2.0), -Vec3A::unit_z(), 0.0);
+        let (t, b1, b2) = intersect_triangle(&r, v0, v1, v2, 0.001, f32::MAX).unwrap();
+        assert!((t - 2.0).abs() < 1e-6);
+        assert!((b1 - 0.25).abs() < 1e-6);
+        assert!((b2 - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn triangle_misses_outside_edges_and_range() {
+        let (v0, v1, v2) = unit_triangle();
+        let down = -Vec3A::unit_z();
+        let outside = Ray::new(Vec3A::new(0.75, 0.75, 1.0), down, 0.0);
+        assert!(intersect_triangle(&outside, v0, v1, v2, 0.001, f32::MAX).is_none());
+        let parallel = Ray::new(Vec3A::new(-1.0, 0.25, 0.0), Vec3A::unit_x(), 0.0);
+        assert!(intersect_triangle(&parallel, v0, v1, v2, 0.001, f32::MAX).is_none());
+        let near = Ray::new(Vec3A::new(0.25, 0.25, 1.0), down, 0.0);
+        assert!(intersect_triangle(&near, v0, v1, v2, 0.001, 0.5).is_none());
+        let behind = Ray::new(Vec3A::new(0.25, 0.25, -1.0), down, 0.0);
+        assert!(intersect_triangle(&behind, v0, v1, v2, 0.001, f32::MAX).is_none());
+    }
+
+    #[test]
+    fn mesh_hit_from_behind_faces_the_ray() {
+        let (v0, v1, v2) = unit_triangle();
+        let m = mesh(Mesh::new(vec![v0, v1, v2], Vec::new(), Vec::new(), vec![[0, 1, 2]]));
+        let origin = position(0.0, 0.0, 0.0);
+        let front = Ray::new(Vec3A::new(0.25, 0.25, 1.0), -Vec3A::unit_z(), 0.0);
+        let rec = hit(&origin, None, &m, &front, 0.001, f32::MAX).unwrap();
+        assert!(rec.front_face);
+        assert!((rec.normal - Vec3A::unit_z()).length() < 1e-6);
+        let back = Ray::new(Vec3A::new(0.25, 0.25, -1.0), Vec3A::unit_z(), 0.0);
+        let rec = hit(&origin, None, &m, &back, 0.001, f32::MAX).unwrap();
+        assert!(!rec.front_face);
+        assert!((rec.normal + Vec3A::unit_z()).length() < 1e-6);
+    }
+}
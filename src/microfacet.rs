@@ -0,0 +1,162 @@
+use glam::Vec3A;
+
+use utils::orthonormal_basis;
+
+use std::f32::consts::PI;
+
+/// Below this roughness surfaces are treated as perfectly smooth, as the distribution of normals
+/// becomes too sharp to evaluate
+pub const MIN_ALPHA: f32 = 1e-3;
+
+/// A shading frame with the normal along z, in which microfacet distributions are evaluated
+pub struct Frame {
+    s: Vec3A,
+    t: Vec3A,
+    n: Vec3A,
+}
+
+impl Frame {
+    pub fn new(n: Vec3A) -> Frame {
+        let (s, t) = orthonormal_basis(n);
+        Frame { s, t, n }
+    }
+
+    pub fn to_local(&self, v: Vec3A) -> Vec3A {
+        Vec3A::new(v.dot(self.s), v.dot(self.t), v.dot(self.n))
+    }
+
+    pub fn to_world(&self, v: Vec3A) -> Vec3A {
+        v.x() * self.s + v.y() * self.t + v.z() * self.n
+    }
+}
+
+/// The isotropic GGX, or Trowbridge-Reitz, distribution of microfacet normals with width `alpha`,
+/// in a frame with the macro-surface normal along z
+#[derive(Clone, Copy, Debug)]
+pub struct Ggx {
+    pub alpha: f32,
+}
+
+impl Ggx {
+    /// The distribution of a perceptually linear `roughness`, whose square is the width
+    pub fn from_roughness(roughness: f32) -> Ggx {
+        Ggx { alpha: roughness * roughness }
+    }
+
+    pub fn is_smooth(&self) -> bool {
+        self.alpha < MIN_ALPHA
+    }
+
+    /// The density of microfacets with normal `m` per unit of macro-surface area and solid angle
+    pub fn d(&self, m: Vec3A) -> f32 {
+        if m.z() <= 0.0 {
+            return 0.0;
+        }
+        let alpha2 = self.alpha * self.alpha;
+        let denominator = m.z() * m.z() * (alpha2 - 1.0) + 1.0;
+        alpha2 / (PI * denominator * denominator)
+    }
+
+    // Smith's auxiliary function, from which the masking of either direction follows
+    fn lambda(&self, w: Vec3A) -> f32 {
+        let cos2 = w.z() * w.z();
+        if cos2 <= 0.0 {
+            return f32::INFINITY;
+        }
+        let tan2 = (1.0 - cos2).max(0.0) / cos2;
+        0.5 * ((1.0 + self.alpha * self.alpha * tan2).sqrt() - 1.0)
+    }
+
+    /// The fraction of microfacets facing `w` that are visible from it
+    pub fn g1(&self, w: Vec3A) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// The height-correlated fraction of microfacets visible from both `wo` and `wi`
+    pub fn g2(&self, wo: Vec3A, wi: Vec3A) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// The density of normals visible from `wo`, as sampled by `sample_visible_normal`
+    pub fn visible_pdf(&self, wo: Vec3A, m: Vec3A) -> f32 {
+        if wo.z() <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * wo.dot(m).max(0.0) * self.d(m) / wo.z()
+    }
+
+    /// A microfacet normal distributed by the normals visible from `wo`, which must be above the
+    /// surface. Heitz, Sampling the GGX Distribution of Visible Normals.
+    pub fn sample_visible_normal(&self, wo: Vec3A, u: (f32, f32)) -> Vec3A {
+        // Stretch the view so that the distribution becomes a hemisphere
+        let v = Vec3A::new(self.alpha * wo.x(), self.alpha * wo.y(), wo.z()).normalize();
+        let length2 = v.x() * v.x() + v.y() * v.y();
+        let t1 = if length2 > 0.0 {
+            Vec3A::new(-v.y(), v.x(), 0.0) / length2.sqrt()
+        } else {
+            Vec3A::new(1.0, 0.0, 0.0)
+        };
+        let t2 = v.cross(t1);
+        // A point on the disk projected from the visible half of the hemisphere
+        let r = u.0.sqrt();
+        let phi = 2.0 * PI * u.1;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + v.z());
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+        let n = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * v;
+        Vec3A::new(self.alpha * n.x(), self.alpha * n.y(), n.z().max(1e-6)).normalize()
+    }
+}
+
+/// The unpolarised reflectance of a conductor with complex index of refraction `eta` + i`k`, for
+/// each channel, at the cosine of the angle of incidence
+pub fn fresnel_conductor(cosine: f32, eta: Vec3A, k: Vec3A) -> Vec3A {
+    let cos2 = cosine.clamp(0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let channel = |eta: f32, k: f32| {
+        let t0 = eta * eta - k * k - sin2;
+        let a2b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+        let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+        let t1 = a2b2 + cos2;
+        let t2 = 2.0 * cosine.clamp(0.0, 1.0) * a;
+        let rs = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+        0.5 * (rs + rp)
+    };
+    Vec3A::new(channel(eta.x(), k.x()), channel(eta.y(), k.y()), channel(eta.z(), k.z()))
+}
+
+/// The unpolarised reflectance of a dielectric interface at the cosine of the angle of incidence,
+/// where `eta` is the ratio of the index of refraction across the interface to that on the side
+/// of incidence. Total internal reflection gives one.
+pub fn fresnel_dielectric(cosine: f32, eta: f32) -> f32 {
+    let cos_i = cosine.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    0.5 * (rs * rs + rp * rp)
+}
+
+/// The direction in which light leaving along `wo` arrives when refracted through a facet with
+/// normal `m` on the same side as `wo`, or None for total internal reflection. `eta` is as for
+/// `fresnel_dielectric`.
+pub fn refract(wo: Vec3A, m: Vec3A, eta: f32) -> Option<Vec3A> {
+    let cos_i = wo.dot(m);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-wo / eta + (cos_i / eta - cos_t) * m)
+}
+
+/// The mirror image of `wo` about the normal `m`
+pub fn reflect(wo: Vec3A, m: Vec3A) -> Vec3A {
+    2.0 * wo.dot(m) * m - wo
+}
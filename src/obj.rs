@@ -0,0 +1,168 @@
+use failure::Error;
+use glam::{Vec2, Vec3A};
+use specs::prelude::*;
+
+use std::collections::HashMap;
+use std::fs;
+
+use components::Position;
+use hitable::{mesh, Hitable};
+use material::{lambertian, Material};
+use mesh::Mesh;
+
+pub fn default_obj_material() -> Material {
+    lambertian(Vec3A::new(0.7, 0.7, 0.7))
+}
+
+// The triangles of one object in the file, with their vertices deduplicated by their position,
+// texture coordinate and normal indices
+#[derive(Default)]
+struct ObjObject {
+    vertex_indices: HashMap<(usize, Option<usize>, Option<usize>), u32>,
+    vertices: Vec<(usize, Option<usize>, Option<usize>)>,
+    indices: Vec<[u32; 3]>,
+}
+
+impl ObjObject {
+    fn vertex(&mut self, key: (usize, Option<usize>, Option<usize>)) -> u32 {
+        let vertices = &mut self.vertices;
+        *self.vertex_indices.entry(key).or_insert_with(|| {
+            vertices.push(key);
+            (vertices.len() - 1) as u32
+        })
+    }
+
+    fn into_mesh(self, positions: &[Vec3A], uvs: &[Vec2], normals: &[Vec3A]) -> Mesh {
+        // Normals and texture coordinates are only used if every vertex has them
+        let has_uvs = self.vertices.iter().all(|v| v.1.is_some());
+        let has_normals = self.vertices.iter().all(|v| v.2.is_some());
+        Mesh::new(
+            self.vertices.iter().map(|v| positions[v.0]).collect(),
+            if has_normals {
+                self.vertices.iter().map(|v| normals[v.2.unwrap()].normalize()).collect()
+            } else {
+                Vec::new()
+            },
+            if has_uvs {
+                self.vertices.iter().map(|v| uvs[v.1.unwrap()]).collect()
+            } else {
+                Vec::new()
+            },
+            self.indices,
+        )
+    }
+}
+
+// OBJ indices are 1-based, or negative to count back from the most recent element
+fn parse_index(token: &str, count: usize, line: usize) -> Result<usize, Error> {
+    let index: i64 = token.parse().map_err(|_| format_err!("{}: invalid index '{}'", line, token))?;
+    let resolved = if index < 0 { count as i64 + index } else { index - 1 };
+    if resolved < 0 || resolved >= count as i64 {
+        return Err(format_err!("{}: index {} out of range", line, index));
+    }
+    Ok(resolved as usize)
+}
+
+fn parse_floats(tokens: &[&str], count: usize, line: usize) -> Result<Vec<f32>, Error> {
+    if tokens.len() < count {
+        return Err(format_err!("{}: expected {} values", line, count));
+    }
+    tokens[..count].iter()
+        .map(|t| t.parse().map_err(|_| format_err!("{}: invalid number '{}'", line, t)))
+        .collect()
+}
+
+/// Parse the polygonal faces of a Wavefront OBJ file into one triangle mesh per object (`o`) or
+/// group (`g`). Polygons are triangulated as fans. Materials, lines and points are ignored.
+pub fn parse_obj(text: &str) -> Result<Vec<Mesh>, Error> {
+    parse_obj_objects(text, true)
+}
+
+fn parse_obj_objects(text: &str, split_objects: bool) -> Result<Vec<Mesh>, Error> {
+    let mut positions = Vec::new();
+    let mut uvs = Vec::new();
+    let mut normals = Vec::new();
+    let mut objects = vec![ObjObject::default()];
+
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        let tokens: Vec<&str> = line.split('#').next().unwrap_or("").split_whitespace().collect();
+        if tokens.is_empty() {
+            continue;
+        }
+        let args = &tokens[1..];
+        match tokens[0] {
+            "v" => {
+                let v = parse_floats(args, 3, number)?;
+                positions.push(Vec3A::new(v[0], v[1], v[2]));
+            },
+            "vt" => {
+                let v = parse_floats(args, 2, number)?;
+                uvs.push(Vec2::new(v[0], v[1]));
+            },
+            "vn" => {
+                let v = parse_floats(args, 3, number)?;
+                normals.push(Vec3A::new(v[0], v[1], v[2]));
+            },
+            "o" | "g" if split_objects && !objects.last().unwrap().indices.is_empty() => {
+                objects.push(ObjObject::default());
+            },
+            "f" => {
+                if args.len() < 3 {
+                    return Err(format_err!("{}: a face needs at least 3 vertices", number));
+                }
+                let object = objects.last_mut().unwrap();
+                let mut face = Vec::with_capacity(args.len());
+                for arg in args {
+                    let mut parts = arg.split('/');
+                    let position = parse_index(parts.next().unwrap_or(""), positions.len(), number)?;
+                    let uv = match parts.next() {
+                        Some(t) if !t.is_empty() => Some(parse_index(t, uvs.len(), number)?),
+                        _ => None,
+                    };
+                    let normal = match parts.next() {
+                        Some(t) if !t.is_empty() => Some(parse_index(t, normals.len(), number)?),
+                        _ => None,
+                    };
+                    face.push(object.vertex((position, uv, normal)));
+                }
+                for j in 1..face.len() - 1 {
+                    object.indices.push([face[0], face[j], face[j + 1]]);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    Ok(objects.into_iter()
+        .filter(|object| !object.indices.is_empty())
+        .map(|object| object.into_mesh(&positions, &uvs, &normals))
+        .collect())
+}
+
+/// Load all of the faces in an OBJ file as a single mesh
+pub fn load_obj_mesh(path: &str) -> Result<Hitable, Error> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format_err!("failed to read OBJ file {}: {}", path, e))?;
+    let mut meshes = parse_obj_objects(&text, false).map_err(|e| format_err!("{}:{}", path, e))?;
+    match meshes.pop() {
+        Some(m) => Ok(mesh(m)),
+        None => Err(format_err!("{}: no faces", path)),
+    }
+}
+
+/// Load each object in an OBJ file as a mesh entity at the origin with the default material
+pub fn load_obj(world: &mut World, path: &str) -> Result<Vec<Entity>, Error> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format_err!("failed to read OBJ file {}: {}", path, e))?;
+    let meshes = parse_obj(&text).map_err(|e| format_err!("{}:{}", path, e))?;
+    Ok(meshes.into_iter()
+        .map(|m| {
+            world.create_entity()
+                .with(Position(Vec3A::zero()))
+                .with(mesh(m))
+                .with(default_obj_material())
+                .build()
+        })
+        .collect())
+}
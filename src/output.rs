@@ -0,0 +1,249 @@
+use failure::Error;
+use glam::Vec3A;
+use image::Rgb;
+use image::hdr::HdrEncoder;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Image file formats. PNG holds the 8-bit display image, the others hold linear radiance as
+/// floating point.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ImageFormat {
+    // OpenEXR, uncompressed 32-bit float RGB
+    Exr,
+    // Radiance RGBE
+    Hdr,
+    // Portable float map, 32-bit float RGB
+    Pfm,
+    #[default]
+    Png,
+}
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Exr => "exr",
+            ImageFormat::Hdr => "hdr",
+            ImageFormat::Pfm => "pfm",
+            ImageFormat::Png => "png",
+        }
+    }
+
+    pub fn is_linear(self) -> bool {
+        self != ImageFormat::Png
+    }
+
+    /// The format named by a file name's extension, if it names one
+    pub fn from_path(path: &str) -> Option<ImageFormat> {
+        Path::new(path).extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| extension.to_lowercase().parse().ok())
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ImageFormat, String> {
+        match s {
+            "exr" => Ok(ImageFormat::Exr),
+            "hdr" => Ok(ImageFormat::Hdr),
+            "pfm" => Ok(ImageFormat::Pfm),
+            "png" => Ok(ImageFormat::Png),
+            _ => Err(format!("unknown image format '{}'", s)),
+        }
+    }
+}
+
+/// Write linear RGB pixels, given row by row from the top, in one of the floating point formats
+pub fn save_linear_image(filename: &str, format: ImageFormat, width: usize, height: usize, pixels: &[Vec3A]) -> Result<(), Error> {
+    let file = File::create(filename)
+        .map_err(|e| format_err!("failed to create {}: {}", filename, e))?;
+    let mut w = BufWriter::new(file);
+    match format {
+        ImageFormat::Exr => write_exr(&mut w, width, height, pixels)?,
+        ImageFormat::Hdr => write_hdr(&mut w, width, height, pixels)?,
+        ImageFormat::Pfm => write_pfm(&mut w, width, height, pixels)?,
+        ImageFormat::Png => return Err(format_err!("{}: PNG is not a linear image format", filename)),
+    }
+    w.flush()?;
+    Ok(())
+}
+
+fn write_hdr<W: Write>(w: &mut W, width: usize, height: usize, pixels: &[Vec3A]) -> Result<(), Error> {
+    let data: Vec<Rgb<f32>> = pixels.iter()
+        .map(|p| Rgb([p.x().max(0.0), p.y().max(0.0), p.z().max(0.0)]))
+        .collect();
+    HdrEncoder::new(w).encode(&data, width, height)?;
+    Ok(())
+}
+
+fn write_pfm<W: Write>(w: &mut W, width: usize, height: usize, pixels: &[Vec3A]) -> Result<(), Error> {
+    // A negative scale means little-endian. Rows are stored from the bottom up.
+    write!(w, "PF\n{} {}\n-1.0\n", width, height)?;
+    for row in pixels.chunks(width).rev() {
+        for p in row {
+            for &c in &[p.x(), p.y(), p.z()] {
+                w.write_all(&c.to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_attribute<W: Write>(w: &mut W, name: &str, kind: &str, value: &[u8]) -> Result<(), Error> {
+    w.write_all(name.as_bytes())?;
+    w.write_all(&[0])?;
+    w.write_all(kind.as_bytes())?;
+    w.write_all(&[0])?;
+    w.write_all(&(value.len() as i32).to_le_bytes())?;
+    w.write_all(value)?;
+    Ok(())
+}
+
+// A single-part scanline OpenEXR file without compression, one scanline per chunk
+fn write_exr<W: Write>(w: &mut W, width: usize, height: usize, pixels: &[Vec3A]) -> Result<(), Error> {
+    const FLOAT: i32 = 2;
+    // Channels must be sorted by name
+    const CHANNELS: [&str; 3] = ["B", "G", "R"];
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&20_000_630i32.to_le_bytes());
+    header.extend_from_slice(&2i32.to_le_bytes());
+
+    let mut channels = Vec::new();
+    for name in &CHANNELS {
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        channels.extend_from_slice(&FLOAT.to_le_bytes());
+        // pLinear and three reserved bytes
+        channels.extend_from_slice(&[0, 0, 0, 0]);
+        channels.extend_from_slice(&1i32.to_le_bytes());
+        channels.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channels.push(0);
+    let mut window = Vec::new();
+    for &v in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+    write_attribute(&mut header, "channels", "chlist", &channels)?;
+    write_attribute(&mut header, "compression", "compression", &[0])?;
+    write_attribute(&mut header, "dataWindow", "box2i", &window)?;
+    write_attribute(&mut header, "displayWindow", "box2i", &window)?;
+    write_attribute(&mut header, "lineOrder", "lineOrder", &[0])?;
+    write_attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes())?;
+    write_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8])?;
+    write_attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes())?;
+    header.push(0);
+    w.write_all(&header)?;
+
+    // The offset table gives the position in the file of each scanline's chunk
+    let chunk_size = 8 + CHANNELS.len() * width * 4;
+    let first_chunk = header.len() + height * 8;
+    for y in 0..height {
+        w.write_all(&((first_chunk + y * chunk_size) as u64).to_le_bytes())?;
+    }
+    for (y, row) in pixels.chunks(width).enumerate() {
+        w.write_all(&(y as i32).to_le_bytes())?;
+        w.write_all(&((chunk_size - 8) as i32).to_le_bytes())?;
+        for channel in (0..CHANNELS.len()).rev() {
+            for p in row {
+                w.write_all(&p[channel].to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::hdr::HdrDecoder;
+
+    // A gradient whose pixels all differ, given row by row from the top
+    fn test_image(width: usize, height: usize) -> Vec<Vec3A> {
+        (0..width * height)
+            .map(|i| Vec3A::new(i as f32 + 0.25, 2.0 * i as f32 + 0.5, 0.125))
+            .collect()
+    }
+
+    fn f32_at(bytes: &[u8], offset: usize) -> f32 {
+        f32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+    }
+
+    #[test]
+    fn pfm_is_little_endian_from_the_bottom_row() {
+        let (width, height) = (3, 2);
+        let pixels = test_image(width, height);
+        let mut bytes = Vec::new();
+        write_pfm(&mut bytes, width, height, &pixels).unwrap();
+        let header = b"PF\n3 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], &header[..]);
+        assert_eq!(bytes.len(), header.len() + width * height * 12);
+        let data = &bytes[header.len()..];
+        for y in 0..height {
+            for x in 0..width {
+                let p = pixels[(height - 1 - y) * width + x];
+                let offset = (y * width + x) * 12;
+                assert_eq!(f32_at(data, offset), p.x());
+                assert_eq!(f32_at(data, offset + 4), p.y());
+                assert_eq!(f32_at(data, offset + 8), p.z());
+            }
+        }
+    }
+
+    #[test]
+    fn hdr_round_trips_within_rgbe_precision() {
+        // Wide enough for the run length encoded scanlines
+        let (width, height) = (16, 3);
+        let mut pixels = test_image(width, height);
+        pixels[0] = Vec3A::new(-1.0, 0.0, 1.0);
+        let mut bytes = Vec::new();
+        write_hdr(&mut bytes, width, height, &pixels).unwrap();
+        let decoder = HdrDecoder::new(&bytes[..]).unwrap();
+        let metadata = decoder.metadata();
+        assert_eq!((metadata.width as usize, metadata.height as usize), (width, height));
+        let decoded = decoder.read_image_hdr().unwrap();
+        assert_eq!(decoded.len(), pixels.len());
+        // Negative radiance is clamped to zero
+        assert_eq!(decoded[0].0, [0.0, 0.0, 1.0]);
+        for (p, d) in pixels.iter().zip(decoded.iter()).skip(1) {
+            // The shared exponent keeps about 8 bits of the largest channel
+            let tolerance = p.max_element() / 128.0;
+            for c in 0..3 {
+                assert!((p[c] - d.0[c]).abs() <= tolerance, "{} decoded as {:?}", p, d.0);
+            }
+        }
+    }
+
+    #[test]
+    fn exr_offsets_point_at_scanlines_of_planar_channels() {
+        let (width, height) = (2, 3);
+        let pixels = test_image(width, height);
+        let mut bytes = Vec::new();
+        write_exr(&mut bytes, width, height, &pixels).unwrap();
+        // Magic number and version 2, single-part scanline
+        assert_eq!(&bytes[..8], &[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+        let chunk_size = 8 + 3 * width * 4;
+        let first_chunk = bytes.len() - height * chunk_size;
+        let table = first_chunk - height * 8;
+        for y in 0..height {
+            let mut offset = [0; 8];
+            offset.copy_from_slice(&bytes[table + y * 8..table + y * 8 + 8]);
+            let offset = u64::from_le_bytes(offset) as usize;
+            assert_eq!(offset, first_chunk + y * chunk_size);
+            assert_eq!(&bytes[offset..offset + 4], &(y as i32).to_le_bytes());
+            // Channels are stored B, G, R, each for the whole scanline
+            let data = offset + 8;
+            for x in 0..width {
+                let p = pixels[y * width + x];
+                assert_eq!(f32_at(&bytes, data + 4 * x), p.z());
+                assert_eq!(f32_at(&bytes, data + 4 * (width + x)), p.y());
+                assert_eq!(f32_at(&bytes, data + 4 * (2 * width + x)), p.x());
+            }
+        }
+    }
+}
@@ -1,15 +1,19 @@
 use glam::Vec3A;
 
+#[derive(Clone, Copy, Debug)]
 pub struct Ray {
     pub origin: Vec3A,
     pub direction: Vec3A,
+    // The moment within the camera's shutter interval at which the ray is traced
+    pub time: f32,
 }
 
 impl Ray {
-    pub fn new(o: Vec3A, d: Vec3A) -> Ray {
+    pub fn new(o: Vec3A, d: Vec3A, time: f32) -> Ray {
         Ray {
             origin: o,
             direction: d,
+            time,
         }
     }
 
@@ -23,6 +27,7 @@ impl Default for Ray {
         Ray::new(
             Vec3A::zero(),
             Vec3A::zero(),
+            0.0,
         )
     }
 }
@@ -1,7 +1,15 @@
+use glam::Vec3A;
 use hibitset::BitSet;
+use specs::Entity;
 
+use bvh::Bvh;
+use color::{quantize, srgb_oetf, tonemap, Tonemap};
+use material::{henyey_greenstein, Material};
+use output::ImageFormat;
 use timers::Timers;
 
+use std::str::FromStr;
+
 #[derive(Debug, Default)]
 pub struct PerfTimers(pub Timers);
 
@@ -23,11 +31,202 @@ pub struct TargetFrameDuration(pub f64);
 #[derive(Debug, Default)]
 pub struct SamplesToProcessPerFrame(pub u64);
 
+/// Pixels are not sampled beyond this many samples, 0 for no limit. Stopping every pixel at
+/// exactly the same count makes the image independent of how the samples were spread over frames.
+#[derive(Debug, Default)]
+pub struct SampleLimit(pub usize);
+
+impl SampleLimit {
+    pub fn allows_more(&self, sample_count: f32) -> bool {
+        self.0 == 0 || sample_count < self.0 as f32
+    }
+}
+
+/// Seeds every pseudorandom choice, from the scene to each sample of each pixel, so that a seed
+/// gives the same image on every run
+#[derive(Debug, Default)]
+pub struct Seed(pub u64);
+
 #[derive(Debug, Default)]
 pub struct BufferOutput(pub Vec<u8>);
 
 #[derive(Debug, Default)]
 pub struct ImageFilePrefix(pub String);
 
+#[derive(Debug, Default)]
+pub struct ImageFileFormat(pub ImageFormat);
+
+/// Whether the AOVs are saved next to the beauty image
+#[derive(Debug, Default)]
+pub struct SaveAovs(pub bool);
+
 #[derive(Debug, Default)]
 pub struct PixelsToProcess(pub BitSet);
+
+/// The denoised beauty image, row by row from the top, or empty when denoising is off
+#[derive(Debug, Default)]
+pub struct DenoisedImage(pub Vec<Vec3A>);
+
+/// Bump this whenever something that affects the rendered image changes but is not otherwise
+/// detected, so that the accumulated samples are cleared.
+#[derive(Debug, Default)]
+pub struct SceneVersion(pub u64);
+
+#[derive(Debug, Default)]
+pub struct SceneBvh(pub Bvh<Entity>);
+
+/// The emissive entities that are sampled directly, with their ids also in a set for lookups
+#[derive(Debug, Default)]
+pub struct Lights {
+    pub entities: Vec<Entity>,
+    pub mask: BitSet,
+}
+
+/// What a ray that leaves the scene sees
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Background {
+    // A white to light blue gradient from the horizon upwards
+    #[default]
+    Sky,
+    Color(Vec3A),
+}
+
+/// A homogeneous participating medium filling the whole scene, off while `density` is zero. Rays
+/// that leave the scene always collide with it in the end, so the background is not seen through
+/// it and it suits enclosed scenes. A `ConstantMedium` makes a bounded bank of fog.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Fog {
+    pub density: f32,
+    pub albedo: Vec3A,
+    // The Henyey-Greenstein asymmetry of its scattering
+    pub g: f32,
+}
+
+impl Fog {
+    pub fn material(&self) -> Material {
+        henyey_greenstein(self.albedo, self.g)
+    }
+}
+
+#[derive(Debug)]
+pub struct Convergence {
+    // Mean over all pixels of the variance of each pixel's mean luminance
+    pub variance: f32,
+    // Mean over all pixels of the relative standard error of each pixel's mean luminance.
+    // Infinite until every pixel has at least two samples.
+    pub relative_error: f32,
+    // The number of pixels that adaptive sampling has not yet retired
+    pub active_pixels: usize,
+}
+
+impl Default for Convergence {
+    fn default() -> Convergence {
+        Convergence {
+            variance: f32::INFINITY,
+            relative_error: f32::INFINITY,
+            active_pixels: 0,
+        }
+    }
+}
+
+/// Spend the samples of each frame on the pixels with the highest estimated relative error,
+/// retiring those whose error has dropped below `threshold`
+#[derive(Debug)]
+pub struct AdaptiveSampling {
+    pub enabled: bool,
+    pub threshold: f32,
+    // Pixels are not retired before they have this many samples, as their error estimates are unreliable
+    pub min_samples: f32,
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> AdaptiveSampling {
+        AdaptiveSampling {
+            enabled: false,
+            threshold: 0.01,
+            min_samples: 16.0,
+        }
+    }
+}
+
+impl AdaptiveSampling {
+    pub fn is_active(&self, sample_count: f32, relative_error: f32) -> bool {
+        !self.enabled || sample_count < self.min_samples || relative_error >= self.threshold
+    }
+}
+
+/// The image, or arbitrary output variable, that is displayed
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum View {
+    #[default]
+    Beauty,
+    Albedo,
+    Normal,
+    Depth,
+    ObjectId,
+}
+
+impl View {
+    pub const ALL: [View; 5] = [View::Beauty, View::Albedo, View::Normal, View::Depth, View::ObjectId];
+
+    pub fn next(self) -> View {
+        View::ALL[(self as usize + 1) % View::ALL.len()]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            View::Beauty => "beauty",
+            View::Albedo => "albedo",
+            View::Normal => "normal",
+            View::Depth => "depth",
+            View::ObjectId => "id",
+        }
+    }
+}
+
+impl FromStr for View {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<View, String> {
+        View::ALL.iter()
+            .find(|view| view.name() == s)
+            .cloned()
+            .ok_or_else(|| format!("unknown view '{}'", s))
+    }
+}
+
+/// How linear radiance becomes the 8-bit sRGB image shown in the preview and saved as PNG
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DisplaySettings {
+    // In stops
+    pub exposure: f32,
+    pub tonemap: Tonemap,
+    // Add noise of up to half a quantisation step to hide banding in smooth gradients
+    pub dither: bool,
+    pub view: View,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> DisplaySettings {
+        DisplaySettings {
+            exposure: 0.0,
+            tonemap: Tonemap::None,
+            dither: false,
+            view: View::Beauty,
+        }
+    }
+}
+
+impl DisplaySettings {
+    /// `noise` is in [0, 1) and is only used for dithering
+    pub fn to_rgba8(&self, color: Vec3A, noise: f32) -> [u8; 4] {
+        let c = tonemap(self.tonemap, self.exposure.exp2() * color);
+        let dither = if self.dither { noise - 0.5 } else { 0.0 };
+        [
+            quantize(srgb_oetf(c.x()), dither),
+            quantize(srgb_oetf(c.y()), dither),
+            quantize(srgb_oetf(c.z()), dither),
+            255,
+        ]
+    }
+}
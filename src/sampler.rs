@@ -0,0 +1,276 @@
+use std::str::FromStr;
+
+use blue_noise::BlueNoise;
+
+// The first primes, one per Halton dimension. Further dimensions fall back to hashed random numbers.
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+];
+// Stratified samples come in batches of STRATA 1D strata or STRATA_2D x STRATA_2D 2D strata
+const STRATA_2D: u32 = 8;
+const STRATA: u32 = STRATA_2D * STRATA_2D;
+// 1/phi in 0.32 fixed point, the additive constant of the golden ratio sequence
+const GOLDEN: u32 = 2_654_435_769;
+
+/// Where the sample values for every random decision made along a path come from
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SamplerKind {
+    // Uncorrelated pseudorandom numbers
+    Independent,
+    // Blue noise over the image for every dimension, low discrepancy over the samples of a pixel
+    #[default]
+    BlueNoise,
+    // Jittered strata, in shuffled batches of 64 samples per pixel
+    Stratified,
+    // The Halton sequence, randomised per pixel by Cranley-Patterson rotation
+    Halton,
+    // The Sobol sequence with hash-based Owen scrambling, padded per dimension
+    Sobol,
+}
+
+impl FromStr for SamplerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SamplerKind, String> {
+        match s {
+            "independent" => Ok(SamplerKind::Independent),
+            "blue_noise" => Ok(SamplerKind::BlueNoise),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            _ => Err(format!("unknown sampler '{}'", s)),
+        }
+    }
+}
+
+// Chris Wellons' lowbias32 integer hash
+fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    x
+}
+
+fn hash_combine(seed: u32, value: u32) -> u32 {
+    hash(seed ^ value.wrapping_add(0x9e37_79b9).wrapping_add(seed << 6).wrapping_add(seed >> 2))
+}
+
+// A 0.32 fixed point number to a float in [0, 1)
+fn to_float(x: u32) -> f32 {
+    (x >> 8) as f32 / 16_777_216.0
+}
+
+// Kensler's hash-based permutation of [0, length)
+fn permute(mut i: u32, length: u32, seed: u32) -> u32 {
+    let mut w = length - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < length {
+            break;
+        }
+    }
+    (i.wrapping_add(seed)) % length
+}
+
+fn radical_inverse(base: u32, mut index: u32) -> f32 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_n = 1.0;
+    let mut reversed = 0u64;
+    while index > 0 {
+        reversed = reversed * base as u64 + (index % base) as u64;
+        index /= base;
+        inv_base_n *= inv_base;
+    }
+    (reversed as f64 * inv_base_n) as f32
+}
+
+// The first two dimensions of the Sobol sequence: van der Corput and the one for polynomial x + 1
+const fn sobol_directions() -> [[u32; 32]; 2] {
+    let mut v = [[0u32; 32]; 2];
+    v[1][0] = 1 << 31;
+    let mut i = 0;
+    while i < 32 {
+        v[0][i] = 1 << (31 - i);
+        if i > 0 {
+            v[1][i] = v[1][i - 1] ^ (v[1][i - 1] >> 1);
+        }
+        i += 1;
+    }
+    v
+}
+
+const SOBOL: [[u32; 32]; 2] = sobol_directions();
+
+fn sobol(mut index: u32, dimension: usize) -> u32 {
+    if dimension == 0 {
+        return index.reverse_bits();
+    }
+    let mut x = 0;
+    while index != 0 {
+        x ^= SOBOL[dimension][index.trailing_zeros() as usize];
+        index &= index - 1;
+    }
+    x
+}
+
+// Burley, Practical Hash-based Owen Scrambling
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+/// The sample values for one path, that is one sample of one pixel. Each call to `next_1d` or
+/// `next_2d` moves on to the next dimension of the sample.
+pub struct Sampler<'a> {
+    kind: SamplerKind,
+    blue_noise: &'a BlueNoise,
+    x: usize,
+    y: usize,
+    index: u32,
+    pixel_seed: u32,
+    dimension: u32,
+}
+
+impl<'a> Sampler<'a> {
+    pub fn new(kind: SamplerKind, blue_noise: &'a BlueNoise, seed: u64, x: usize, y: usize, index: u32) -> Sampler<'a> {
+        let seed = hash_combine(hash(seed as u32), (seed >> 32) as u32);
+        Sampler {
+            kind,
+            blue_noise,
+            x,
+            y,
+            index,
+            pixel_seed: hash_combine(hash_combine(seed, x as u32), y as u32),
+            dimension: 0,
+        }
+    }
+
+    fn seed(&self, dimension: u32) -> u32 {
+        hash_combine(self.pixel_seed, dimension)
+    }
+
+    // A counter-based pseudorandom number, unique to this seed, pixel, sample and dimension, so
+    // that it does not depend on which thread traces the sample or in which order
+    fn hashed_random(&self, dimension: u32) -> f32 {
+        to_float(hash_combine(self.seed(dimension), self.index))
+    }
+
+    // Each dimension reads the blue noise tile at a different offset so that dimensions are not
+    // correlated with each other
+    fn blue_noise_texel(&self, dimension: u32) -> (usize, usize) {
+        if dimension == 0 {
+            return (self.x, self.y);
+        }
+        let offset = hash(dimension);
+        let size = self.blue_noise.size();
+        (
+            self.x + (offset & 0xffff) as usize % size,
+            self.y + (offset >> 16) as usize % size,
+        )
+    }
+
+    fn stratified(&self, dimension: u32, strata: u32) -> u32 {
+        let batch = self.index / strata;
+        permute(self.index % strata, strata, hash_combine(self.seed(dimension), batch))
+    }
+
+    pub fn next_1d(&mut self) -> f32 {
+        let d = self.dimension;
+        self.dimension += 1;
+        match self.kind {
+            SamplerKind::Independent => self.hashed_random(d),
+            SamplerKind::BlueNoise => {
+                let (x, y) = self.blue_noise_texel(d);
+                let value = (self.blue_noise.value(x, y) * 4_294_967_296.0) as u32;
+                to_float(value.wrapping_add(self.index.wrapping_mul(GOLDEN)))
+            },
+            SamplerKind::Stratified => {
+                let stratum = self.stratified(d, STRATA);
+                (stratum as f32 + self.hashed_random(d)) / STRATA as f32
+            },
+            SamplerKind::Halton => match PRIMES.get(d as usize) {
+                Some(&base) => {
+                    let value = radical_inverse(base, self.index) + to_float(self.seed(d));
+                    value - value.floor()
+                },
+                None => self.hashed_random(d),
+            },
+            SamplerKind::Sobol => {
+                let seed = self.seed(d);
+                let index = nested_uniform_scramble(self.index, seed);
+                to_float(nested_uniform_scramble(sobol(index, 0), hash_combine(seed, 1)))
+            },
+        }
+    }
+
+    /// A pseudorandom value whatever the kind of sampler, for decisions whose number varies from
+    /// path to path, such as the steps of tracking through media, where the dimensions of the
+    /// other samplers would be correlated with each other
+    pub fn next_independent(&mut self) -> f32 {
+        let d = self.dimension;
+        self.dimension += 1;
+        self.hashed_random(d)
+    }
+
+    pub fn next_2d(&mut self) -> (f32, f32) {
+        let d = self.dimension;
+        match self.kind {
+            SamplerKind::Independent | SamplerKind::Halton => (self.next_1d(), self.next_1d()),
+            SamplerKind::BlueNoise => {
+                self.dimension += 2;
+                let (x, y) = self.blue_noise_texel(d);
+                self.blue_noise.sample_2d(x, y, self.index)
+            },
+            SamplerKind::Stratified => {
+                self.dimension += 2;
+                let stratum = self.stratified(d, STRATA);
+                (
+                    ((stratum % STRATA_2D) as f32 + self.hashed_random(d)) / STRATA_2D as f32,
+                    ((stratum / STRATA_2D) as f32 + self.hashed_random(d + 1)) / STRATA_2D as f32,
+                )
+            },
+            SamplerKind::Sobol => {
+                self.dimension += 2;
+                let seed = self.seed(d);
+                let index = nested_uniform_scramble(self.index, seed);
+                (
+                    to_float(nested_uniform_scramble(sobol(index, 0), hash_combine(seed, 1))),
+                    to_float(nested_uniform_scramble(sobol(index, 1), hash_combine(seed, 2))),
+                )
+            },
+        }
+    }
+}
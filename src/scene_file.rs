@@ -0,0 +1,544 @@
+//! A line-based text format for scene descriptions. Blank lines and everything after a `#` are
+//! ignored. A line containing only `render`, `camera` or `entity`, or `geometry name`, starts a
+//! new section and every following line up to the next section sets one property of it:
+//!
+//! ```text
+//! render
+//!     width 640
+//!     height 320
+//!     spp 64
+//!
+//! camera
+//!     look_from 13 2 3
+//!     look_at 0 0 0
+//!     vfov 20
+//!     aperture 0.1
+//!
+//! entity
+//!     position 0 1 0
+//!     sphere 1.0
+//!     dielectric 1.5
+//! ```
+//!
+//! Render properties: `width`, `height`, `samples`, `spp`, `framerate`, `sky on|off`,
+//! `background r g b` for a constant colour instead of the sky and `fog density r g b g` to fill
+//! the scene with a medium of the given albedo and Henyey-Greenstein asymmetry.
+//! Camera properties: `look_from`, `look_at`, `vup`, `vfov`, `aperture`, `focus_dist`, which
+//! defaults to the distance between `look_from` and `look_at`, and `shutter open close`, the
+//! interval of time over which moving entities are blurred.
+//! Entity properties: `position x y z` (defaults to the origin), a hitable (`sphere radius`,
+//! `triangle x0 y0 z0 x1 y1 z1 x2 y2 z2`, `mesh file.obj` relative to the scene file, `plane nx ny nz`,
+//! `quad ux uy uz vx vy vz` with a corner at the position and edges u and v, `rect x|y|z width height`
+//! for an axis-aligned quad facing along the axis, `disk nx ny nz radius` or
+//! `box x0 y0 z0 x1 y1 z1` between two corners relative to the position), optionally turned and
+//! stretched about the position by `rotate ax ay az degrees` anticlockwise about an axis and
+//! `scale sx sy sz`, applied in the order given, `velocity x y z` to move from the position at
+//! time zero, and a
+//! material (`lambertian r g b`, `metal r g b fuzz`, `dielectric ref_idx` or the emissive
+//! `diffuse_light r g b`). Meshes without a material get a light grey `lambertian` one.
+//! Rough surfaces with GGX microfacets are `conductor eta_r eta_g eta_b k_r k_g k_b roughness`,
+//! or `conductor aluminium|copper|gold|silver roughness`, and `rough_dielectric ref_idx roughness`,
+//! where a roughness of zero is perfectly smooth.
+//! `medium density` turns the hitable into the boundary of a volume of smoke or haze, which takes
+//! the material `isotropic r g b` or `henyey_greenstein r g b g`. The density can vary through the
+//! volume, scaled by `density_noise scale` for Perlin turbulence of features about 1 / `scale`
+//! across, or by `density_grid file.vol` for a voxel grid in Mitsuba's grid volume format or
+//! `density_grid file nx ny nz` for raw little-endian floats spanning the unit cube. A grid
+//! without a hitable fills its own bounds, and `medium` defaults to 1 when there is a grid or
+//! noise.
+//!
+//! A `geometry name` section holds a single hitable that is loaded once and shared by every entity
+//! with the hitable `instance name`, each placed by its own position and transform:
+//!
+//! ```text
+//! geometry teapot
+//!     mesh teapot.obj
+//!
+//! entity
+//!     position 2 0 0
+//!     rotate 0 1 0 90
+//!     instance teapot
+//! ```
+//!
+//! The colour of `lambertian` and `metal` can instead be a texture: `checker r g b r g b scale`
+//! for a 3D checkerboard of cubes of side `scale`, `noise r g b scale`, `turbulence r g b scale`
+//! or `marble r g b scale` for Perlin noise with features about 1 / `scale` across, or
+//! `image file.png`, relative to the scene file, mapped by the surface's texture coordinates:
+//!
+//! ```text
+//! entity
+//!     sphere 1.0
+//!     metal checker 0.9 0.9 0.9 0.2 0.3 0.1 0.25 0.05
+//! ```
+
+use failure::Error;
+use glam::Vec3A;
+use specs::prelude::*;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::{FromStr, SplitWhitespace};
+use std::sync::Arc;
+
+use camera::CameraParams;
+use components::{Position, Transform, Velocity};
+use hitable::{constant_medium, cuboid, disk, heterogeneous_medium, instance, plane, quad, rect, sphere, triangle, Hitable};
+use obj::{default_obj_material, load_obj_mesh};
+use material::{conductor, dielectric, diffuse_light, henyey_greenstein, isotropic, lambertian, metal, named_conductor, rough_dielectric, Material};
+use resources::{Background, Fog};
+use scenes::{builtin_scene, RenderSettings, SceneDescription};
+use texture::{Texture, Textures};
+use volume::{DensityField, VoxelGrid};
+
+/// Load the built-in scene of the given name, or otherwise the scene description file at that path.
+pub fn load_scene(world: &mut World, scene: &str, seed: u64) -> Result<SceneDescription, Error> {
+    if let Some(description) = builtin_scene(world, scene, seed) {
+        return Ok(description);
+    }
+    let text = fs::read_to_string(scene)
+        .map_err(|e| format_err!("failed to read scene file {}: {}", scene, e))?;
+    let base = Path::new(scene).parent().unwrap_or(Path::new(""));
+    parse_scene(world, &text, base).map_err(|e| format_err!("{}:{}", scene, e))
+}
+
+struct Line<'t> {
+    number: usize,
+    keyword: &'t str,
+    args: SplitWhitespace<'t>,
+}
+
+impl<'t> Line<'t> {
+    fn error(&self, message: &str) -> Error {
+        format_err!("{}: {}: {}", self.number, self.keyword, message)
+    }
+
+    fn next<T: FromStr>(&mut self) -> Result<T, Error> {
+        match self.args.next() {
+            Some(arg) => arg.parse().map_err(|_| self.error(&format!("invalid argument '{}'", arg))),
+            None => Err(self.error("missing argument")),
+        }
+    }
+
+    fn next_vec3(&mut self) -> Result<Vec3A, Error> {
+        Ok(Vec3A::new(self.next()?, self.next()?, self.next()?))
+    }
+
+    // The complex index of refraction of a conductor, either the name of a metal or eta and k
+    fn next_conductor(&mut self) -> Result<(Vec3A, Vec3A), Error> {
+        match self.args.clone().next() {
+            Some(name) if name.parse::<f32>().is_err() => {
+                self.args.next();
+                named_conductor(name).ok_or_else(|| self.error(&format!("unknown metal '{}'", name)))
+            },
+            _ => Ok((self.next_vec3()?, self.next_vec3()?)),
+        }
+    }
+
+    // Either a colour or the name of a texture followed by its arguments
+    fn next_texture(&mut self, textures: &mut Textures, base: &Path) -> Result<Texture, Error> {
+        let name = match self.args.clone().next() {
+            Some(name) if name.parse::<f32>().is_err() => name,
+            _ => return Ok(Texture::Solid(self.next_vec3()?)),
+        };
+        self.args.next();
+        let texture = match name {
+            "checker" => Texture::Checker { even: self.next_vec3()?, odd: self.next_vec3()?, scale: self.next()? },
+            "noise" => Texture::Noise { color: self.next_vec3()?, scale: self.next()? },
+            "turbulence" => Texture::Turbulence { color: self.next_vec3()?, scale: self.next()? },
+            "marble" => Texture::Marble { color: self.next_vec3()?, scale: self.next()? },
+            "image" => {
+                let path = base.join(self.next::<String>()?);
+                textures.load_image(&path.to_string_lossy()).map_err(|e| self.error(&e.to_string()))?
+            },
+            _ => return Err(self.error(&format!("unknown texture '{}'", name))),
+        };
+        Ok(texture)
+    }
+
+    fn end(&mut self) -> Result<(), Error> {
+        match self.args.next() {
+            Some(arg) => Err(self.error(&format!("unexpected argument '{}'", arg))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct EntityDescription {
+    line: usize,
+    position: Option<Position>,
+    transform: Option<Transform>,
+    velocity: Option<Velocity>,
+    density: Option<f32>,
+    field: Option<DensityField>,
+    hitable: Option<Hitable>,
+    material: Option<Material>,
+}
+
+// The hitable described by a line, or None if the line is not a hitable
+fn parse_hitable(line: &mut Line, base: &Path, geometries: &HashMap<String, Arc<Hitable>>) -> Result<Option<Hitable>, Error> {
+    let hitable = match line.keyword {
+        "sphere" => sphere(line.next()?),
+        "triangle" => triangle(line.next_vec3()?, line.next_vec3()?, line.next_vec3()?),
+        "plane" => plane(line.next_vec3()?),
+        "quad" => quad(line.next_vec3()?, line.next_vec3()?),
+        "rect" => {
+            let axis = match line.next::<String>()?.as_ref() {
+                "x" => 0,
+                "y" => 1,
+                "z" => 2,
+                _ => return Err(line.error("expected x, y or z")),
+            };
+            rect(axis, line.next()?, line.next()?)
+        },
+        "disk" => disk(line.next_vec3()?, line.next()?),
+        "box" => cuboid(line.next_vec3()?, line.next_vec3()?),
+        "mesh" => {
+            let path = base.join(line.next::<String>()?);
+            load_obj_mesh(&path.to_string_lossy()).map_err(|e| line.error(&e.to_string()))?
+        },
+        "instance" => {
+            let name = line.next::<String>()?;
+            match geometries.get(&name) {
+                Some(geometry) => instance(geometry),
+                None => return Err(line.error(&format!("unknown geometry '{}'", name))),
+            }
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(hitable))
+}
+
+// Meshes, and instances of them, without a material get a default one
+fn is_mesh(hitable: &Hitable) -> bool {
+    match hitable {
+        Hitable::Mesh(_) => true,
+        Hitable::Instance(h) => is_mesh(h),
+        _ => false,
+    }
+}
+
+impl EntityDescription {
+    fn set_property(&mut self, line: &mut Line, base: &Path, textures: &mut Textures, geometries: &HashMap<String, Arc<Hitable>>) -> Result<(), Error> {
+        if let Some(hitable) = parse_hitable(line, base, geometries)? {
+            self.hitable = Some(hitable);
+            return Ok(());
+        }
+        match line.keyword {
+            "position" => self.position = Some(Position(line.next_vec3()?)),
+            "rotate" => {
+                let axis = line.next_vec3()?;
+                if axis == Vec3A::zero() {
+                    return Err(line.error("expected a non-zero axis"));
+                }
+                let rotation = Transform::rotation(axis, line.next()?);
+                self.transform = Some(self.transform.unwrap_or_default().then(&rotation));
+            },
+            "velocity" => self.velocity = Some(Velocity(line.next_vec3()?)),
+            "medium" => self.density = Some(line.next()?),
+            "density_grid" => {
+                let path = base.join(line.next::<String>()?);
+                let path = path.to_string_lossy();
+                let grid = if path.ends_with(".vol") {
+                    VoxelGrid::load_vol(&path)
+                } else {
+                    VoxelGrid::load_raw(&path, [line.next()?, line.next()?, line.next()?])
+                };
+                self.field = Some(DensityField::Grid(Arc::new(grid.map_err(|e| line.error(&e.to_string()))?)));
+            },
+            "density_noise" => self.field = Some(DensityField::noise(0, line.next()?)),
+            "scale" => {
+                let scale = line.next_vec3()?;
+                if scale.cmpeq(Vec3A::zero()).any() {
+                    return Err(line.error("expected a non-zero scale along every axis"));
+                }
+                let scale = Transform::scale(scale);
+                self.transform = Some(self.transform.unwrap_or_default().then(&scale));
+            },
+            "lambertian" => self.material = Some(lambertian(line.next_texture(textures, base)?)),
+            "metal" => self.material = Some(metal(line.next_texture(textures, base)?, line.next()?)),
+            "dielectric" => self.material = Some(dielectric(line.next()?)),
+            "conductor" => {
+                let (eta, k) = line.next_conductor()?;
+                self.material = Some(conductor(eta, k, line.next()?));
+            },
+            "rough_dielectric" => self.material = Some(rough_dielectric(line.next()?, line.next()?)),
+            "diffuse_light" => self.material = Some(diffuse_light(line.next_vec3()?)),
+            "isotropic" => self.material = Some(isotropic(line.next_texture(textures, base)?)),
+            "henyey_greenstein" => {
+                self.material = Some(henyey_greenstein(line.next_texture(textures, base)?, line.next()?));
+            },
+            _ => return Err(line.error("unknown entity property")),
+        }
+        Ok(())
+    }
+
+    fn build(self, world: &mut World) -> Result<Entity, Error> {
+        let line = self.line;
+        // Grids are bounded by their own box unless they are given a hitable
+        let hitable = match (self.hitable, &self.field) {
+            (Some(hitable), _) => hitable,
+            (None, Some(DensityField::Grid(grid))) => cuboid(grid.bounds().min, grid.bounds().max),
+            (None, _) => return Err(format_err!("{}: entity: no hitable", line)),
+        };
+        let hitable = match (self.field, self.density) {
+            (Some(field), density) => heterogeneous_medium(hitable, field, density.unwrap_or(1.0)),
+            (None, Some(density)) => constant_medium(hitable, density),
+            (None, None) => hitable,
+        };
+        let material = match (self.material, &hitable) {
+            (Some(material), _) => material,
+            (None, hitable) if is_mesh(hitable) => default_obj_material(),
+            (None, _) => return Err(format_err!("{}: entity: no material", line)),
+        };
+        let mut builder = world.create_entity()
+            .with(self.position.unwrap_or(Position(Vec3A::zero())))
+            .with(hitable)
+            .with(material);
+        if let Some(transform) = self.transform {
+            builder = builder.with(transform);
+        }
+        if let Some(velocity) = self.velocity {
+            builder = builder.with(velocity);
+        }
+        Ok(builder.build())
+    }
+}
+
+// Geometry that entities can share by instancing it
+struct GeometryDescription {
+    line: usize,
+    name: String,
+    hitable: Option<Hitable>,
+}
+
+impl GeometryDescription {
+    fn build(self, geometries: &mut HashMap<String, Arc<Hitable>>) -> Result<(), Error> {
+        let line = self.line;
+        let hitable = self.hitable
+            .ok_or_else(|| format_err!("{}: geometry: no hitable", line))?;
+        geometries.insert(self.name, Arc::new(hitable));
+        Ok(())
+    }
+}
+
+enum Section {
+    None,
+    Render,
+    Camera,
+    Entity(Box<EntityDescription>),
+    Geometry(Box<GeometryDescription>),
+}
+
+/// Parse a scene description. Relative paths in it are relative to `base`.
+pub fn parse_scene(world: &mut World, text: &str, base: &Path) -> Result<SceneDescription, Error> {
+    // Images are added to the world's textures as they are loaded
+    let mut textures = world.remove::<Textures>().unwrap_or_default();
+    let description = parse_scene_with_textures(world, &mut textures, text, base);
+    world.insert(textures);
+    description
+}
+
+fn parse_scene_with_textures(world: &mut World, textures: &mut Textures, text: &str, base: &Path) -> Result<SceneDescription, Error> {
+    let mut camera = CameraParams::default();
+    let mut focus_dist = None;
+    let mut has_camera = false;
+    let mut render = RenderSettings::default();
+    let mut entities = Vec::new();
+    let mut geometries = HashMap::new();
+    let mut section = Section::None;
+
+    for (i, text) in text.lines().enumerate() {
+        let text = text.split('#').next().unwrap_or("");
+        let mut args = text.split_whitespace();
+        let keyword = match args.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        let mut line = Line { number: i + 1, keyword, args };
+
+        let next_section = match keyword {
+            "render" => Some(Section::Render),
+            "camera" => {
+                has_camera = true;
+                Some(Section::Camera)
+            },
+            "entity" => Some(Section::Entity(Box::new(EntityDescription { line: line.number, ..Default::default() }))),
+            "geometry" => Some(Section::Geometry(Box::new(GeometryDescription {
+                line: line.number,
+                name: line.next()?,
+                hitable: None,
+            }))),
+            _ => None,
+        };
+        if let Some(next_section) = next_section {
+            line.end()?;
+            match std::mem::replace(&mut section, next_section) {
+                Section::Entity(entity) => entities.push(entity.build(world)?),
+                Section::Geometry(geometry) => geometry.build(&mut geometries)?,
+                _ => {},
+            }
+            continue;
+        }
+
+        match section {
+            Section::None => return Err(line.error("property outside of a render, camera or entity section")),
+            Section::Render => match keyword {
+                "width" => render.width = Some(line.next()?),
+                "height" => render.height = Some(line.next()?),
+                "samples" => render.samples = Some(line.next()?),
+                "spp" => render.spp = Some(line.next()?),
+                "framerate" => render.framerate = Some(line.next()?),
+                "sky" => render.background = Some(match line.next::<String>()?.as_ref() {
+                    "on" => Background::Sky,
+                    "off" => Background::Color(Vec3A::zero()),
+                    _ => return Err(line.error("expected on or off")),
+                }),
+                "background" => render.background = Some(Background::Color(line.next_vec3()?)),
+                "fog" => render.fog = Some(Fog { density: line.next()?, albedo: line.next_vec3()?, g: line.next()? }),
+                _ => return Err(line.error("unknown render property")),
+            },
+            Section::Camera => match keyword {
+                "look_from" => camera.look_from = line.next_vec3()?,
+                "look_at" => camera.look_at = line.next_vec3()?,
+                "vup" => camera.vup = line.next_vec3()?,
+                "vfov" => camera.vfov = line.next()?,
+                "aperture" => camera.aperture = line.next()?,
+                "focus_dist" => focus_dist = Some(line.next()?),
+                "shutter" => {
+                    camera.shutter_open = line.next()?;
+                    camera.shutter_close = line.next()?;
+                },
+                _ => return Err(line.error("unknown camera property")),
+            },
+            Section::Entity(ref mut entity) => entity.set_property(&mut line, base, textures, &geometries)?,
+            Section::Geometry(ref mut geometry) => match parse_hitable(&mut line, base, &geometries)? {
+                Some(hitable) => geometry.hitable = Some(hitable),
+                None => return Err(line.error("unknown geometry property")),
+            },
+        }
+        line.end()?;
+    }
+    match section {
+        Section::Entity(entity) => entities.push(entity.build(world)?),
+        Section::Geometry(geometry) => geometry.build(&mut geometries)?,
+        _ => {},
+    }
+
+    if has_camera {
+        camera.focus_dist = focus_dist.unwrap_or((camera.look_from - camera.look_at).length());
+    }
+    Ok(SceneDescription { camera, render, entities })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use components::register_components;
+
+    fn parse(text: &str) -> Result<(World, SceneDescription), Error> {
+        let mut world = World::new();
+        register_components(&mut world);
+        let description = parse_scene(&mut world, text, Path::new(""))?;
+        Ok((world, description))
+    }
+
+    fn parse_error(text: &str) -> String {
+        match parse(text) {
+            Ok(_) => panic!("expected an error parsing {:?}", text),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn sections_set_render_camera_and_entities() {
+        let (world, description) = parse("
+            # A comment
+            render
+                width 64   # and a trailing one
+                spp 16
+                sky off
+            camera
+                look_from 0 0 5
+                look_at 0 0 1
+                vfov 30
+            entity
+                position 1 2 3
+                sphere 0.5
+                lambertian 0.1 0.2 0.3
+            entity
+                sphere 1
+                dielectric 1.5
+        ").unwrap();
+        assert_eq!(description.render.width, Some(64));
+        assert_eq!(description.render.height, None);
+        assert_eq!(description.render.spp, Some(16));
+        assert!(matches!(description.render.background, Some(Background::Color(c)) if c == Vec3A::zero()));
+        assert_eq!(description.camera.look_from, Vec3A::new(0.0, 0.0, 5.0));
+        assert_eq!(description.camera.vfov, 30.0);
+        assert_eq!(description.camera.focus_dist, 4.0);
+
+        assert_eq!(description.entities.len(), 2);
+        let positions = world.read_storage::<Position>();
+        let hitables = world.read_storage::<Hitable>();
+        let materials = world.read_storage::<Material>();
+        let (first, second) = (description.entities[0], description.entities[1]);
+        assert_eq!(positions.get(first).unwrap().0, Vec3A::new(1.0, 2.0, 3.0));
+        assert!(matches!(hitables.get(first), Some(Hitable::Sphere(s)) if s.radius == 0.5));
+        assert!(matches!(materials.get(first), Some(Material::Lambertian(_))));
+        assert_eq!(positions.get(second).unwrap().0, Vec3A::zero());
+        assert!(matches!(materials.get(second), Some(Material::Dielectric(d)) if d.ref_idx == 1.5));
+    }
+
+    #[test]
+    fn focus_dist_overrides_the_distance_to_look_at() {
+        let (_, description) = parse("camera\nlook_from 0 0 5\nlook_at 0 0 0\nfocus_dist 2\n").unwrap();
+        assert_eq!(description.camera.focus_dist, 2.0);
+    }
+
+    #[test]
+    fn instances_share_their_geometry() {
+        let (world, description) = parse("
+            geometry ball
+                sphere 2
+            entity
+                instance ball
+                metal 0.5 0.5 0.5 0
+            entity
+                position 4 0 0
+                instance ball
+                metal 0.5 0.5 0.5 0
+        ").unwrap();
+        let hitables = world.read_storage::<Hitable>();
+        match (hitables.get(description.entities[0]), hitables.get(description.entities[1])) {
+            (Some(Hitable::Instance(a)), Some(Hitable::Instance(b))) => {
+                assert!(Arc::ptr_eq(a, b));
+                assert!(matches!(**a, Hitable::Sphere(ref s) if s.radius == 2.0));
+            },
+            _ => panic!("expected two instances"),
+        }
+    }
+
+    #[test]
+    fn errors_name_the_line_and_keyword() {
+        assert_eq!(parse_error("\nwidth 64"), "2: width: property outside of a render, camera or entity section");
+        assert_eq!(parse_error("entity\ninstance teapot"), "2: instance: unknown geometry 'teapot'");
+        assert_eq!(parse_error("entity\nsphere 1 2"), "2: sphere: unexpected argument '2'");
+        assert_eq!(parse_error("render extra"), "1: render: unexpected argument 'extra'");
+        assert_eq!(parse_error("entity\nsphere"), "2: sphere: missing argument");
+        assert_eq!(parse_error("entity\nsphere big"), "2: sphere: invalid argument 'big'");
+        assert_eq!(parse_error("camera\nzoom 2"), "2: zoom: unknown camera property");
+        assert_eq!(parse_error("render\nsky maybe"), "2: sky: expected on or off");
+        assert_eq!(parse_error("entity\nsphere 1\nmetal sparkly 0"), "3: metal: unknown texture 'sparkly'");
+        assert_eq!(parse_error("entity\nsphere 1\nscale 0 1 1"), "3: scale: expected a non-zero scale along every axis");
+        assert_eq!(parse_error("entity\nsphere 1\nrotate 0 0 0 90"), "3: rotate: expected a non-zero axis");
+    }
+
+    #[test]
+    fn incomplete_sections_are_errors() {
+        assert_eq!(parse_error("entity\nsphere 1\n\nentity\nsphere 1\nlambertian 1 1 1"), "1: entity: no material");
+        assert_eq!(parse_error("entity\nlambertian 1 1 1"), "1: entity: no hitable");
+        assert_eq!(parse_error("geometry empty\nentity"), "1: geometry: no hitable");
+    }
+}
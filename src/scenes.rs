@@ -1,58 +1,249 @@
 use glam::Vec3A;
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
 use specs::{Entity, World};
 use specs::prelude::*;
 
-use components::position;
-use hitable::sphere;
-use material::{dielectric, lambertian, metal};
-use utils::random_float_01;
+use camera::CameraParams;
+use components::{position, Transform};
+use hitable::{constant_medium, cuboid, plane, rect, sphere};
+use material::{dielectric, diffuse_light, isotropic, lambertian, metal, Material};
+use resources::{Background, Fog};
 
-pub fn balls(world: &mut World) -> Vec<Entity> {
+/// Render settings that a scene may specify. Anything left unset falls back to the command line
+/// or the renderer's defaults.
+#[derive(Debug, Default)]
+pub struct RenderSettings {
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub samples: Option<usize>,
+    pub spp: Option<usize>,
+    pub framerate: Option<f64>,
+    pub background: Option<Background>,
+    pub fog: Option<Fog>,
+}
+
+pub struct SceneDescription {
+    pub camera: CameraParams,
+    pub render: RenderSettings,
+    pub entities: Vec<Entity>,
+}
+
+/// Build the named built-in scene. `seed` seeds the scenes that are randomly generated.
+pub fn builtin_scene(world: &mut World, name: &str, seed: u64) -> Option<SceneDescription> {
+    let (camera, entities) = match name {
+        "balls" => {
+            let look_from = Vec3A::new(3.0, 3.0, 2.0);
+            let look_at = Vec3A::new(0.0, 0.0, -1.0);
+            let camera = CameraParams {
+                look_from,
+                look_at,
+                vup: Vec3A::unit_y(),
+                vfov: 20.0,
+                aperture: 2.0,
+                focus_dist: (look_from - look_at).length(),
+                ..Default::default()
+            };
+            (camera, balls(world))
+        },
+        "random" => (CameraParams::default(), random_scene(world, seed)),
+        "cornell" | "cornell_smoke" => {
+            let camera = CameraParams {
+                look_from: Vec3A::new(278.0, 278.0, -800.0),
+                look_at: Vec3A::new(278.0, 278.0, 0.0),
+                vup: Vec3A::unit_y(),
+                vfov: 40.0,
+                aperture: 0.0,
+                focus_dist: 10.0,
+                ..Default::default()
+            };
+            let render = RenderSettings {
+                width: Some(400),
+                height: Some(400),
+                background: Some(Background::Color(Vec3A::zero())),
+                ..Default::default()
+            };
+            let entities = if name == "cornell" { cornell_box(world) } else { cornell_smoke(world) };
+            return Some(SceneDescription { camera, render, entities });
+        },
+        "simple_light" => {
+            let camera = CameraParams {
+                look_from: Vec3A::new(26.0, 3.0, 6.0),
+                look_at: Vec3A::new(0.0, 2.0, 0.0),
+                vup: Vec3A::unit_y(),
+                vfov: 20.0,
+                aperture: 0.0,
+                focus_dist: 10.0,
+                ..Default::default()
+            };
+            let render = RenderSettings {
+                background: Some(Background::Color(Vec3A::zero())),
+                ..Default::default()
+            };
+            return Some(SceneDescription { camera, render, entities: simple_light(world) });
+        },
+        _ => return None,
+    };
+    Some(SceneDescription { camera, render: RenderSettings::default(), entities })
+}
+
+pub fn simple_light(world: &mut World) -> Vec<Entity> {
+    vec![
+        world.create_entity()
+            .with(position(0.0, 0.0, 0.0))
+            .with(plane(Vec3A::unit_y()))
+            .with(lambertian(Vec3A::new(0.5, 0.5, 0.5)))
+            .build(),
+        world.create_entity()
+            .with(position(0.0, 2.0, 0.0))
+            .with(sphere(2.0))
+            .with(lambertian(Vec3A::new(0.1, 0.2, 0.5)))
+            .build(),
+        world.create_entity()
+            .with(position(-2.0, 1.0, 3.0))
+            .with(sphere(1.0))
+            .with(metal(Vec3A::new(0.8, 0.6, 0.2), 0.1))
+            .build(),
+        world.create_entity()
+            .with(position(0.0, 7.0, 0.0))
+            .with(sphere(2.0))
+            .with(diffuse_light(Vec3A::new(4.0, 4.0, 4.0)))
+            .build(),
+        world.create_entity()
+            .with(position(3.0, 1.0, -3.0))
+            .with(sphere(0.5))
+            .with(diffuse_light(Vec3A::new(8.0, 2.0, 1.0)))
+            .build(),
+    ]
+}
+
+// The walls of the Cornell box, 555 units on a side and open towards -z, and a light in the
+// ceiling from `light_corner` along z by `light_size.0` and along x by `light_size.1`
+fn cornell_walls(
+    world: &mut World,
+    light_corner: Vec3A,
+    light_size: (f32, f32),
+    light: Material,
+) -> Vec<Entity> {
+    let red = lambertian(Vec3A::new(0.65, 0.05, 0.05));
+    let white = lambertian(Vec3A::new(0.73, 0.73, 0.73));
+    let green = lambertian(Vec3A::new(0.12, 0.45, 0.15));
+    // Lowest corner, axis, width, height and material of each wall and of the light
+    let walls = [
+        (Vec3A::new(555.0, 0.0, 0.0), 0, 555.0, 555.0, green),
+        (Vec3A::new(0.0, 0.0, 0.0), 0, 555.0, 555.0, red),
+        (Vec3A::new(0.0, 0.0, 0.0), 1, 555.0, 555.0, white),
+        (Vec3A::new(0.0, 555.0, 0.0), 1, 555.0, 555.0, white),
+        (Vec3A::new(0.0, 0.0, 555.0), 2, 555.0, 555.0, white),
+        (light_corner, 1, light_size.0, light_size.1, light),
+    ];
     let mut entities = Vec::<Entity>::new();
+    for &(corner, axis, width, height, material) in walls.iter() {
+        entities.push(
+            world.create_entity()
+                .with(position(corner.x(), corner.y(), corner.z()))
+                .with(rect(axis, width, height))
+                .with(material)
+                .build()
+        );
+    }
+    entities
+}
+
+/// The Cornell box with an area light in the ceiling and two boxes turned about their front left
+/// corners
+pub fn cornell_box(world: &mut World) -> Vec<Entity> {
+    let white = lambertian(Vec3A::new(0.73, 0.73, 0.73));
+    let mut entities = cornell_walls(
+        world,
+        Vec3A::new(213.0, 554.0, 227.0),
+        (105.0, 130.0),
+        diffuse_light(Vec3A::new(15.0, 15.0, 15.0)),
+    );
     entities.push(
         world.create_entity()
-            .with(position(0.0, 0.0, -1.0))
-            .with(sphere(0.5))
-            .with(lambertian(Vec3A::new(0.1, 0.2, 0.5)))
+            .with(position(130.0, 0.0, 65.0))
+            .with(Transform::rotation(Vec3A::unit_y(), -18.0))
+            .with(cuboid(Vec3A::zero(), Vec3A::new(165.0, 165.0, 165.0)))
+            .with(white)
             .build()
     );
     entities.push(
         world.create_entity()
-            .with(position(0.0, -100.5, -1.0))
-            .with(sphere(100.0))
-            .with(lambertian(Vec3A::new(0.8, 0.8, 0.0)))
+            .with(position(265.0, 0.0, 295.0))
+            .with(Transform::rotation(Vec3A::unit_y(), 15.0))
+            .with(cuboid(Vec3A::zero(), Vec3A::new(165.0, 330.0, 165.0)))
+            .with(white)
             .build()
     );
+    entities
+}
+
+/// The Cornell box with a larger, dimmer light and the two boxes filled with white and black smoke
+pub fn cornell_smoke(world: &mut World) -> Vec<Entity> {
+    let mut entities = cornell_walls(
+        world,
+        Vec3A::new(113.0, 554.0, 127.0),
+        (305.0, 330.0),
+        diffuse_light(Vec3A::new(7.0, 7.0, 7.0)),
+    );
     entities.push(
         world.create_entity()
-            .with(position(1.0, 0.0, -1.0))
-            .with(sphere(0.5))
-            .with(metal(Vec3A::new(0.8, 0.6, 0.2), 0.0))
+            .with(position(130.0, 0.0, 65.0))
+            .with(Transform::rotation(Vec3A::unit_y(), -18.0))
+            .with(constant_medium(cuboid(Vec3A::zero(), Vec3A::new(165.0, 165.0, 165.0)), 0.01))
+            .with(isotropic(Vec3A::one()))
             .build()
     );
     entities.push(
+        world.create_entity()
+            .with(position(265.0, 0.0, 295.0))
+            .with(Transform::rotation(Vec3A::unit_y(), 15.0))
+            .with(constant_medium(cuboid(Vec3A::zero(), Vec3A::new(165.0, 330.0, 165.0)), 0.01))
+            .with(isotropic(Vec3A::zero()))
+            .build()
+    );
+    entities
+}
+
+pub fn balls(world: &mut World) -> Vec<Entity> {
+    vec![
+        world.create_entity()
+            .with(position(0.0, 0.0, -1.0))
+            .with(sphere(0.5))
+            .with(lambertian(Vec3A::new(0.1, 0.2, 0.5)))
+            .build(),
+        world.create_entity()
+            .with(position(0.0, -100.5, -1.0))
+            .with(sphere(100.0))
+            .with(lambertian(Vec3A::new(0.8, 0.8, 0.0)))
+            .build(),
+        world.create_entity()
+            .with(position(1.0, 0.0, -1.0))
+            .with(sphere(0.5))
+            .with(metal(Vec3A::new(0.8, 0.6, 0.2), 0.0))
+            .build(),
         world.create_entity()
             .with(position(-1.0, 0.0, -1.0))
             .with(sphere(0.5))
             .with(dielectric(1.5))
-            .build()
-    );
-    entities.push(
+            .build(),
         world.create_entity()
             .with(position(-1.0, 0.0, -1.0))
             .with(sphere(-0.45))
             .with(dielectric(1.5))
-            .build()
-    );
-    entities
+            .build(),
+    ]
 }
 
-pub fn random_scene(world: &mut World) -> Vec<Entity> {
+pub fn random_scene(world: &mut World, seed: u64) -> Vec<Entity> {
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut random_float_01 = || rng.gen::<f32>();
     let mut entities = Vec::<Entity>::new();
     entities.push(
         world.create_entity()
-            .with(position(0.0, -1000.0, 0.0))
-            .with(sphere(1000.0))
+            .with(position(0.0, 0.0, 0.0))
+            .with(plane(Vec3A::unit_y()))
             .with(lambertian(Vec3A::new(0.5, 0.5, 0.5)))
             .build()
     );
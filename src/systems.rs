@@ -1,58 +1,626 @@
-use glam::Vec3A;
-use image::{ColorType::Rgba8, save_buffer};
+use failure::Error;
+use glam::{Vec2, Vec3A};
+use hibitset::BitSetLike;
+use image::{ColorType::Rgba8, ImageResult, save_buffer};
 use specs::prelude::*;
+use specs::storage::ComponentEvent;
+use specs::world::Index;
 
+use aabb::Aabb;
+use blue_noise::BlueNoise;
+use bvh::Bvh;
 use camera::Camera;
-use color::Colorf32;
+use color::{luminance, quantize, srgb_oetf};
 use components::*;
-use hitable::{hit, Hitable, HitRecord};
-use material::{Material, scatter};
+use denoise::{denoise_image, Denoise, PixelFeatures};
+use hitable::{bounding_box, direction_pdf, hit, Hitable, HitRecord, is_medium, is_samplable, medium_density, medium_interval, sample_direction};
+use material::{albedo, emitted, eval, Material, scatter};
+use output::{ImageFormat, save_linear_image};
 use ray::Ray;
 use resources::*;
-use utils::{lerp_vec3, random_float_01};
+use sampler::{Sampler, SamplerKind};
+use texture::Textures;
+use utils::{lerp_vec3, power_heuristic};
 
-use std;
+use std::cmp::Ordering;
+use std::time::Instant;
 
-fn color<'a>(
+fn background_color(r: &Ray, background: &Background) -> Vec3A {
+    match *background {
+        Background::Sky => {
+            let unit_direction = r.direction.normalize();
+            let t = 0.5 * (unit_direction.y() + 1.0);
+            lerp_vec3(Vec3A::one(), t, Vec3A::new(0.5, 0.7, 1.0))
+        },
+        Background::Color(c) => c,
+    }
+}
+
+const MAX_DEPTH: u32 = 50;
+
+/// The resources and storages that rays are traced against
+pub type SceneData<'a> = (
+    Read<'a, SceneBvh>,
+    Read<'a, Lights>,
+    Read<'a, Background>,
+    Read<'a, Fog>,
+    ReadStorage<'a, Position>,
+    ReadStorage<'a, Transform>,
+    ReadStorage<'a, Velocity>,
+    ReadStorage<'a, Hitable>,
+    ReadStorage<'a, Material>,
+    Read<'a, Textures>,
+);
+
+struct Scene<'s, 'a: 's> {
+    bvh: &'s SceneBvh,
+    lights: &'s Lights,
+    background: &'s Background,
+    fog: &'s Fog,
+    positions: &'s ReadStorage<'a, Position>,
+    transforms: &'s ReadStorage<'a, Transform>,
+    velocities: &'s ReadStorage<'a, Velocity>,
+    hitables: &'s ReadStorage<'a, Hitable>,
+    materials: &'s ReadStorage<'a, Material>,
+    textures: &'s Textures,
+}
+
+impl<'s, 'a> Scene<'s, 'a> {
+    // Where the entity is at `time`, if it has a position and a hitable
+    fn placement(&self, entity: Entity, time: f32) -> Option<(Position, &'s Hitable)> {
+        let (position, hitable) = (self.positions.get(entity)?, self.hitables.get(entity)?);
+        let position = match self.velocities.get(entity) {
+            Some(velocity) => velocity.position_at(position, time),
+            None => Position(position.0),
+        };
+        Some((position, hitable))
+    }
+
+    // The closest surface that the ray hits, and the stretches of the ray in front of it that pass
+    // through media
+    fn trace(&self, r: &Ray) -> (Option<(Entity, HitRecord)>, Vec<MediumSegment<'s>>) {
+        let mut closest_hit = None;
+        let mut media = Vec::new();
+        self.bvh.0.traverse(r, 0.001, f32::MAX, |&entity, t_max| {
+            let (position, hitable) = self.placement(entity, r.time)?;
+            let transform = self.transforms.get(entity);
+            if is_medium(hitable) {
+                let (t0, t1, majorant) = medium_interval(&position, transform, hitable, r, 0.001, t_max)?;
+                let material = *self.materials.get(entity)?;
+                let heterogeneous = match hitable {
+                    Hitable::HeterogeneousMedium(_) => Some((position.0, transform, hitable)),
+                    _ => None,
+                };
+                media.push(MediumSegment { t0, t1, majorant, material, entity: Some(entity), heterogeneous });
+                return None;
+            }
+            let mut rec = hit(&position, transform, hitable, r, 0.001, t_max)?;
+            // The entity's material replaces any within its hitable, such as those of group items
+            if let Some(material) = self.materials.get(entity) {
+                rec.material = Some(*material);
+            }
+            rec.material?;
+            closest_hit = Some((entity, rec));
+            Some(rec.t)
+        });
+        let t_hit = closest_hit.map_or(f32::INFINITY, |(_, rec)| rec.t);
+        if self.fog.density > 0.0 {
+            let material = self.fog.material();
+            media.push(MediumSegment {
+                t0: 0.001,
+                t1: t_hit,
+                majorant: self.fog.density,
+                material,
+                entity: None,
+                heterogeneous: None,
+            });
+        }
+        // Media found before the closest surface may reach past it
+        for medium in media.iter_mut() {
+            medium.t1 = medium.t1.min(t_hit);
+        }
+        media.retain(|medium| medium.t0 < medium.t1);
+        (closest_hit, media)
+    }
+
+    // The solid angle pdf with which `sample_light` would have chosen the direction of the ray `r`
+    // to the point `rec` on the light `entity`
+    fn light_pdf(&self, entity: Entity, r: &Ray, rec: &HitRecord) -> f32 {
+        if !self.lights.mask.contains(entity.id()) {
+            return 0.0;
+        }
+        match self.placement(entity, r.time) {
+            Some((position, hitable)) => {
+                let transform = self.transforms.get(entity);
+                direction_pdf(&position, transform, hitable, r.origin, rec) / self.lights.entities.len() as f32
+            },
+            None => 0.0,
+        }
+    }
+
+    // Next-event estimation: the radiance arriving at `rec` directly from a randomly chosen light,
+    // weighted against the BSDF having sampled the same direction
+    fn sample_light(&self, r: &Ray, rec: &HitRecord, sampler: &mut Sampler) -> Vec3A {
+        let light_count = self.lights.entities.len();
+        if light_count == 0 {
+            return Vec3A::zero();
+        }
+        let light_choice = sampler.next_1d();
+        let direction_sample = sampler.next_2d();
+        let light = self.lights.entities[((light_choice * light_count as f32) as usize).min(light_count - 1)];
+        let (position, hitable) = match self.placement(light, r.time) {
+            Some(placement) => placement,
+            None => return Vec3A::zero(),
+        };
+        let transform = self.transforms.get(light);
+        let (direction, pdf) = match sample_direction(&position, transform, hitable, rec.p, direction_sample) {
+            Some(sample) => sample,
+            None => return Vec3A::zero(),
+        };
+        let light_pdf = pdf / light_count as f32;
+        let (f_cos, bsdf_pdf) = match eval(r, rec, self.textures, direction) {
+            Some(eval) => eval,
+            None => return Vec3A::zero(),
+        };
+        if f_cos == Vec3A::zero() {
+            return Vec3A::zero();
+        }
+        let shadow_ray = Ray::new(rec.p, direction, r.time);
+        match self.trace(&shadow_ray) {
+            (Some((entity, light_rec)), media) if entity == light => {
+                f_cos * emitted(&light_rec) * transmittance(&shadow_ray, &media, sampler)
+                    * (power_heuristic(light_pdf, bsdf_pdf) / light_pdf)
+            },
+            _ => Vec3A::zero(),
+        }
+    }
+}
+
+// A stretch of a ray through a participating medium
+struct MediumSegment<'s> {
+    t0: f32,
+    t1: f32,
+    // The medium's density, or the largest density within it if it varies
+    majorant: f32,
+    material: Material,
+    // The medium's entity, None for the fog
+    entity: Option<Entity>,
+    // Where a medium of varying density is, to look up its density. None for constant media.
+    heterogeneous: Option<(Vec3A, Option<&'s Transform>, &'s Hitable)>,
+}
+
+impl<'s> MediumSegment<'s> {
+    fn density(&self, p: Vec3A) -> f32 {
+        match self.heterogeneous {
+            Some((position, transform, hitable)) => medium_density(&Position(position), transform, hitable, p),
+            None => self.majorant,
+        }
+    }
+}
+
+// The fraction of light that passes through the media along a ray without colliding with them.
+// Constant media attenuate it exponentially and media of varying density are ratio tracked:
+// it is multiplied by the chance of each tentative collision with the majorant being a null one.
+fn transmittance(r: &Ray, media: &[MediumSegment], sampler: &mut Sampler) -> f32 {
+    let length = r.direction.length();
+    let mut optical_depth = 0.0;
+    let mut ratio = 1.0;
+    for medium in media {
+        if medium.heterogeneous.is_none() {
+            optical_depth += medium.majorant * (medium.t1 - medium.t0);
+            continue;
+        }
+        if medium.majorant <= 0.0 {
+            continue;
+        }
+        let mut t = medium.t0;
+        loop {
+            t -= (1.0 - sampler.next_independent()).ln() / (medium.majorant * length);
+            if t >= medium.t1 {
+                break;
+            }
+            ratio *= 1.0 - medium.density(r.at_t(t)) / medium.majorant;
+        }
+    }
+    ratio * (-optical_depth * length).exp()
+}
+
+// Delta tracking: sample where the ray first collides with the media along it, if it does before
+// they end, and which medium it collides with. Tentative collisions are sampled against the
+// majorants and, in media of varying density, are real ones with the probability of the density
+// there over the majorant. Otherwise the ray carries on from the null collision.
+fn sample_collision<'m, 's>(r: &Ray, media: &'m [MediumSegment<'s>], sampler: &mut Sampler) -> Option<(f32, &'m MediumSegment<'s>)> {
+    if media.is_empty() {
+        return None;
+    }
+    let mut t_start = f32::NEG_INFINITY;
+    let mut u = sampler.next_1d();
+    loop {
+        let (t, medium) = sample_majorant_collision(r, media, t_start, u, sampler)?;
+        if medium.heterogeneous.is_none() || sampler.next_independent() * medium.majorant < medium.density(r.at_t(t)) {
+            return Some((t, medium));
+        }
+        t_start = t;
+        u = sampler.next_independent();
+    }
+}
+
+// Distance tracking with the majorants after `t_start`, from the uniform sample `u`. Where media
+// overlap their majorants add up.
+fn sample_majorant_collision<'m, 's>(
     r: &Ray,
-    position: &ReadStorage<'a, Position>,
-    hitable: &ReadStorage<'a, Hitable>,
-    material: &ReadStorage<'a, Material>,
-    depth: u32,
-) -> Colorf32 {
-
-    let mut closest_hit: Option<HitRecord> = None;
-    let mut t_max = std::f32::MAX;
-    for (position, hitable, material) in (position, hitable, material).join() {
-        if let Some(mut rec) = hit(position, hitable, r, 0.001, t_max) {
-            rec.material = Some(material.clone());
-            if let Some(closest) = closest_hit {
-                if rec.t < closest.t {
-                    t_max = rec.t;
-                    closest_hit = Some(rec);
+    media: &'m [MediumSegment<'s>],
+    t_start: f32,
+    u: f32,
+    sampler: &mut Sampler,
+) -> Option<(f32, &'m MediumSegment<'s>)> {
+    let length = r.direction.length();
+    // The optical depth that the ray travels before colliding
+    let mut remaining = -(1.0 - u).ln();
+    let mut bounds = Vec::with_capacity(2 * media.len());
+    for medium in media {
+        bounds.push(medium.t0);
+        bounds.push(medium.t1);
+    }
+    bounds.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    for pair in bounds.windows(2) {
+        let (a, b) = (pair[0].max(t_start), pair[1]);
+        let covers = |m: &&MediumSegment| m.t0 <= a && b <= m.t1;
+        let density: f32 = media.iter().filter(covers).map(|m| m.majorant).sum();
+        if b <= a || density <= 0.0 {
+            continue;
+        }
+        let depth = density * (b - a) * length;
+        if remaining >= depth {
+            remaining -= depth;
+            continue;
+        }
+        let t = a + remaining / (density * length);
+        // Overlapping media are chosen in proportion to their densities
+        let mut choice = if media.iter().filter(covers).count() > 1 {
+            sampler.next_1d() * density
+        } else {
+            0.0
+        };
+        let mut chosen = None;
+        for medium in media.iter().filter(covers) {
+            chosen = Some(medium);
+            choice -= medium.majorant;
+            if choice <= 0.0 {
+                break;
+            }
+        }
+        return chosen.map(|medium| (t, medium));
+    }
+    None
+}
+
+// The radiance arriving along the camera ray `r`. The features of the first surface it hits, or
+// of the medium it first collides with, are written to `aovs`.
+fn color(r: &Ray, scene: &Scene, sampler: &mut Sampler, aovs: &mut PixelAovs) -> Vec3A {
+    let mut radiance = Vec3A::zero();
+    let mut throughput = Vec3A::one();
+    let mut ray = *r;
+    // The pdf of the BSDF sample that produced `ray`. None for camera rays and specular bounces,
+    // which light sampling cannot produce, so emission they hit is not weighted down.
+    let mut bsdf_pdf: Option<f32> = None;
+    for depth in 0..=MAX_DEPTH {
+        let (surface, media) = scene.trace(&ray);
+        // Rays that pass through media without colliding carry on to the surface, their weight
+        // staying the same as the transmittance cancels with the probability of passing through
+        let (entity, rec) = match (sample_collision(&ray, &media, sampler), surface) {
+            (Some((t, medium)), _) => {
+                let normal = -ray.direction.normalize();
+                (medium.entity, HitRecord::new(t, ray.at_t(t), normal, Vec2::zero(), Some(medium.material)))
+            },
+            (None, Some((entity, rec))) => (Some(entity), rec),
+            (None, None) => {
+                let background = background_color(&ray, scene.background);
+                if depth == 0 {
+                    aovs.albedo = background.min(Vec3A::one());
                 }
-            } else {
-                closest_hit = Some(rec);
+                radiance += throughput * background;
+                break;
             }
+        };
+        if depth == 0 {
+            *aovs = PixelAovs {
+                albedo: albedo(&rec, scene.textures),
+                normal: rec.normal,
+                depth: rec.t * ray.direction.length(),
+                object_id: entity.map_or(NO_OBJECT, |entity| entity.id()),
+            };
+        }
+
+        let emitted = emitted(&rec);
+        if emitted != Vec3A::zero() {
+            let weight = match (bsdf_pdf, entity) {
+                (Some(pdf), Some(entity)) => power_heuristic(pdf, scene.light_pdf(entity, &ray, &rec)),
+                _ => 1.0,
+            };
+            radiance += throughput * emitted * weight;
         }
+
+        if depth == MAX_DEPTH {
+            break;
+        }
+        let srec = match scatter(&ray, &rec, scene.textures, sampler) {
+            Some(srec) => srec,
+            None => break,
+        };
+        if srec.pdf.is_some() {
+            radiance += throughput * scene.sample_light(&ray, &rec, sampler);
+        }
+        throughput *= srec.attenuation;
+        bsdf_pdf = srec.pdf;
+        ray = srec.scattered;
+    }
+    radiance
+}
+
+#[derive(Default)]
+pub struct BuildBvh {
+    position_events: Option<ReaderId<ComponentEvent>>,
+    transform_events: Option<ReaderId<ComponentEvent>>,
+    velocity_events: Option<ReaderId<ComponentEvent>>,
+    hitable_events: Option<ReaderId<ComponentEvent>>,
+    // The shutter interval that the bounds of moving entities cover
+    shutter: (f32, f32),
+    built: bool,
+}
+
+// The bounds of an entity over the whole of the shutter interval
+fn motion_bounds(
+    position: &Position,
+    transform: Option<&Transform>,
+    velocity: Option<&Velocity>,
+    hitable: &Hitable,
+    (open, close): (f32, f32),
+) -> Aabb {
+    match velocity {
+        Some(velocity) => {
+            bounding_box(&velocity.position_at(position, open), transform, hitable)
+                .union(&bounding_box(&velocity.position_at(position, close), transform, hitable))
+        },
+        None => bounding_box(position, transform, hitable),
+    }
+}
+
+impl<'a> System<'a> for BuildBvh {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Camera>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Transform>,
+        ReadStorage<'a, Velocity>,
+        ReadStorage<'a, Hitable>,
+        Write<'a, SceneBvh>,
+        Write<'a, PerfTimers>,
+    );
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        self.position_events = Some(WriteStorage::<Position>::fetch(world).register_reader());
+        self.transform_events = Some(WriteStorage::<Transform>::fetch(world).register_reader());
+        self.velocity_events = Some(WriteStorage::<Velocity>::fetch(world).register_reader());
+        self.hitable_events = Some(WriteStorage::<Hitable>::fetch(world).register_reader());
     }
-    if let Some(rec) = closest_hit {
-        let scatter_option = scatter(r, &rec);
-        if depth < 50 && scatter_option.is_some() {
-            let (attenuation, scattered) = scatter_option.unwrap();
-            return (attenuation * color(&scattered, position, hitable, material, depth + 1)).into();
+
+    fn run(&mut self, (entities, camera, positions, transforms, velocities, hitables, mut bvh, mut timers): Self::SystemData) {
+        let mut rebuild = !self.built;
+        let mut refit = camera.shutter() != self.shutter;
+        self.shutter = camera.shutter();
+        for event in positions.channel().read(self.position_events.as_mut().unwrap())
+            .chain(transforms.channel().read(self.transform_events.as_mut().unwrap()))
+            .chain(velocities.channel().read(self.velocity_events.as_mut().unwrap()))
+            .chain(hitables.channel().read(self.hitable_events.as_mut().unwrap()))
+        {
+            match event {
+                ComponentEvent::Modified(_) => refit = true,
+                ComponentEvent::Inserted(_) | ComponentEvent::Removed(_) => rebuild = true,
+            }
+        }
+        if !rebuild && !refit {
+            return;
+        }
+
+        let timers = &mut timers.0;
+        timers.enter("SYSTEM : BuildBvh");
+        if rebuild {
+            let shutter = self.shutter;
+            let primitives = (&entities, &positions, transforms.maybe(), velocities.maybe(), &hitables).join()
+                .map(|(entity, position, transform, velocity, hitable)| {
+                    (motion_bounds(position, transform, velocity, hitable, shutter), entity)
+                })
+                .collect();
+            bvh.0 = Bvh::build(primitives);
+            self.built = true;
         } else {
-            return Colorf32::new(0.0, 0.0, 0.0, 1.0);
+            bvh.0.refit(|&entity| {
+                match (positions.get(entity), hitables.get(entity)) {
+                    (Some(position), Some(hitable)) => {
+                        motion_bounds(position, transforms.get(entity), velocities.get(entity), hitable, self.shutter)
+                    },
+                    _ => Aabb::empty(),
+                }
+            });
         }
-    } else {
-        let unit_direction = r.direction.normalize();
-        let t = 0.5 * (unit_direction.y() + 1.0);
-        let c = lerp_vec3(Vec3A::one(), t, Vec3A::new(0.5, 0.7, 1.0));
-        return Colorf32::new(c.x(), c.y(), c.z(), 1.0);
+        timers.exit("SYSTEM : BuildBvh");
     }
 }
 
-pub struct PathTrace;
+/// Collects the emissive entities into the `Lights` resource whenever hitables or materials change
+#[derive(Default)]
+pub struct BuildLights {
+    transform_events: Option<ReaderId<ComponentEvent>>,
+    hitable_events: Option<ReaderId<ComponentEvent>>,
+    material_events: Option<ReaderId<ComponentEvent>>,
+    built: bool,
+}
+
+impl<'a> System<'a> for BuildLights {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Transform>,
+        ReadStorage<'a, Hitable>,
+        ReadStorage<'a, Material>,
+        Write<'a, Lights>,
+    );
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        self.transform_events = Some(WriteStorage::<Transform>::fetch(world).register_reader());
+        self.hitable_events = Some(WriteStorage::<Hitable>::fetch(world).register_reader());
+        self.material_events = Some(WriteStorage::<Material>::fetch(world).register_reader());
+    }
+
+    fn run(&mut self, (entities, transforms, hitables, materials, mut lights): Self::SystemData) {
+        let mut changed = transforms.channel().read(self.transform_events.as_mut().unwrap()).count() > 0;
+        changed |= hitables.channel().read(self.hitable_events.as_mut().unwrap()).count() > 0;
+        changed |= materials.channel().read(self.material_events.as_mut().unwrap()).count() > 0;
+        if self.built && !changed {
+            return;
+        }
+        lights.entities.clear();
+        lights.mask.clear();
+        for (entity, transform, hitable, material) in (&entities, transforms.maybe(), &hitables, &materials).join() {
+            if let (Material::DiffuseLight(_), true) = (material, is_samplable(hitable, transform)) {
+                lights.entities.push(entity);
+                lights.mask.add(entity.id());
+            }
+        }
+        self.built = true;
+    }
+}
+
+/// Clears the per-pixel sample accumulation whenever the camera, any scene component or the
+/// `SceneVersion` changes, so that samples of the old and new scene are not mixed.
+#[derive(Default)]
+pub struct ClearAccumulation {
+    position_events: Option<ReaderId<ComponentEvent>>,
+    transform_events: Option<ReaderId<ComponentEvent>>,
+    velocity_events: Option<ReaderId<ComponentEvent>>,
+    hitable_events: Option<ReaderId<ComponentEvent>>,
+    material_events: Option<ReaderId<ComponentEvent>>,
+    camera: Option<Camera>,
+    scene_version: u64,
+}
+
+impl<'a> System<'a> for ClearAccumulation {
+    type SystemData = (
+        Read<'a, Camera>,
+        Read<'a, SceneVersion>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Transform>,
+        ReadStorage<'a, Velocity>,
+        ReadStorage<'a, Hitable>,
+        ReadStorage<'a, Material>,
+        WriteStorage<'a, PixelColor>,
+        WriteStorage<'a, SampleCount>,
+        WriteStorage<'a, PixelSecondMoment>,
+        WriteStorage<'a, PixelAovs>,
+        Write<'a, PixelsToProcess>,
+        Write<'a, PerfTimers>,
+    );
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        self.position_events = Some(WriteStorage::<Position>::fetch(world).register_reader());
+        self.transform_events = Some(WriteStorage::<Transform>::fetch(world).register_reader());
+        self.velocity_events = Some(WriteStorage::<Velocity>::fetch(world).register_reader());
+        self.hitable_events = Some(WriteStorage::<Hitable>::fetch(world).register_reader());
+        self.material_events = Some(WriteStorage::<Material>::fetch(world).register_reader());
+    }
+
+    fn run(
+        &mut self,
+        (
+            camera,
+            scene_version,
+            positions,
+            transforms,
+            velocities,
+            hitables,
+            materials,
+            mut pixel_colors,
+            mut sample_counts,
+            mut second_moments,
+            mut pixel_aovs,
+            mut pixels_to_process,
+            mut timers,
+        ): Self::SystemData
+    ) {
+        // All channels must be read every frame so that their events do not pile up
+        let mut changed = positions.channel().read(self.position_events.as_mut().unwrap()).count() > 0;
+        changed |= transforms.channel().read(self.transform_events.as_mut().unwrap()).count() > 0;
+        changed |= velocities.channel().read(self.velocity_events.as_mut().unwrap()).count() > 0;
+        changed |= hitables.channel().read(self.hitable_events.as_mut().unwrap()).count() > 0;
+        changed |= materials.channel().read(self.material_events.as_mut().unwrap()).count() > 0;
+        changed |= self.camera != Some(*camera);
+        changed |= self.scene_version != scene_version.0;
+        self.camera = Some(*camera);
+        self.scene_version = scene_version.0;
+        if !changed {
+            return;
+        }
+
+        use rayon::prelude::*;
+
+        let timers = &mut timers.0;
+        timers.enter("SYSTEM : ClearAccumulation");
+        (&mut pixel_colors, &mut sample_counts, &mut second_moments, &mut pixel_aovs).par_join().for_each(|(color, sample_count, second_moment, aovs)| {
+            *color = pixel_color(0.0, 0.0, 0.0, 0.0);
+            sample_count.0 = 0.0;
+            second_moment.0 = 0.0;
+            *aovs = PixelAovs::default();
+        });
+        pixels_to_process.0.clear();
+        timers.exit("SYSTEM : ClearAccumulation");
+    }
+}
+
+// The pixels to sample in the next pass over the image. With adaptive sampling this is at most
+// `budget` of the pixels with the highest estimated error, leaving out those that have converged.
+fn next_pass<'a>(
+    adaptive: &AdaptiveSampling,
+    sample_limit: &SampleLimit,
+    budget: usize,
+    pixel_positions: &ReadStorage<'a, PixelPosition>,
+    sample_counts: &WriteStorage<'a, SampleCount>,
+    pixel_errors: &ReadStorage<'a, PixelError>,
+) -> BitSet {
+    if !adaptive.enabled {
+        return (pixel_positions.mask(), sample_counts)
+            .join()
+            .filter(|(_, sample_count)| sample_limit.allows_more(sample_count.0))
+            .map(|(id, _)| id)
+            .collect();
+    }
+    let mut candidates: Vec<(f32, Index)> = (pixel_positions.mask(), sample_counts, pixel_errors)
+        .join()
+        .filter(|(_, sample_count, pixel_error)| {
+            sample_limit.allows_more(sample_count.0) && adaptive.is_active(sample_count.0, pixel_error.0)
+        })
+        .map(|(id, sample_count, pixel_error)| {
+            if sample_count.0 < adaptive.min_samples {
+                (f32::INFINITY, id)
+            } else {
+                (pixel_error.0, id)
+            }
+        })
+        .collect();
+    if candidates.len() > budget {
+        candidates.select_nth_unstable_by(budget, |a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+        candidates.truncate(budget);
+    }
+    candidates.into_iter().map(|(_, id)| id).collect()
+}
+
+#[derive(Default)]
+pub struct PathTrace {
+    // How long the previous run took, in seconds
+    last_duration: Option<f64>,
+}
 
 impl<'a> System<'a> for PathTrace {
     type SystemData = (
@@ -60,12 +628,19 @@ impl<'a> System<'a> for PathTrace {
         Read<'a, Camera>,
         Read<'a, Width>,
         Read<'a, Height>,
-        ReadStorage<'a, Position>,
-        ReadStorage<'a, Hitable>,
-        ReadStorage<'a, Material>,
+        SceneData<'a>,
         Read<'a, TargetFrameDuration>,
         WriteStorage<'a, PixelColor>,
         WriteStorage<'a, SampleCount>,
+        WriteStorage<'a, PixelSecondMoment>,
+        WriteStorage<'a, PixelAovs>,
+        ReadStorage<'a, PixelError>,
+        Read<'a, AdaptiveSampling>,
+        ReadExpect<'a, BlueNoise>,
+        Read<'a, SamplerKind>,
+        Read<'a, SampleLimit>,
+        Read<'a, Seed>,
+        Read<'a, DisplaySettings>,
         Write<'a, SamplesToProcessPerFrame>,
         Write<'a, PixelsToProcess>,
         Write<'a, BufferOutput>,
@@ -79,12 +654,19 @@ impl<'a> System<'a> for PathTrace {
             camera,
             width,
             height,
-            positions,
-            hitables,
-            materials,
+            (bvh, lights, background, fog, positions, transforms, velocities, hitables, materials, textures),
             target_frame_duration,
             mut pixel_colors,
             mut sample_counts,
+            mut second_moments,
+            mut pixel_aovs,
+            pixel_errors,
+            adaptive,
+            blue_noise,
+            sampler_kind,
+            sample_limit,
+            seed,
+            display,
             mut samples_to_process,
             mut pixels_to_process,
             mut buffer_output,
@@ -95,6 +677,7 @@ impl<'a> System<'a> for PathTrace {
 
         let timers = &mut timers.0;
         timers.enter("SYSTEM : PathTrace");
+        let start = Instant::now();
 
         let target_frame_duration = target_frame_duration.0;
         let actual_frame_duration;
@@ -103,11 +686,19 @@ impl<'a> System<'a> for PathTrace {
         } else {
             actual_frame_duration = target_frame_duration;
         }
+        // Scale the samples by how much of the frame is left after everything else in it, such as
+        // denoising, but always spend some of the frame on samples so that the image progresses
+        let scale = match self.last_duration {
+            Some(traced) if traced > 0.0 && traced < actual_frame_duration => {
+                let other = actual_frame_duration - traced;
+                (0.99 * target_frame_duration - other).max(0.25 * target_frame_duration) / traced
+            },
+            _ => 0.99 * target_frame_duration / actual_frame_duration,
+        };
         let samples_to_process = &mut samples_to_process.0;
+        let previous_samples_to_process = *samples_to_process;
         let new_samples_to_process =
-            (*samples_to_process *
-                ((1000.0 * (0.99 * target_frame_duration) / actual_frame_duration) as u64)
-            ) / 1000;
+            ((*samples_to_process * ((1000.0 * scale) as u64)) / 1000).max(1);
         *samples_to_process = new_samples_to_process;
 
         let width = width.0;
@@ -116,69 +707,426 @@ impl<'a> System<'a> for PathTrace {
         let height_f32 = height.0 as f32;
         let height_minus_one = height.0 - 1;
 
+        let scene = Scene {
+            bvh: &bvh,
+            lights: &lights,
+            background: &background,
+            fog: &fog,
+            positions: &positions,
+            transforms: &transforms,
+            velocities: &velocities,
+            hitables: &hitables,
+            materials: &materials,
+            textures: &textures,
+        };
+
         let pixels_to_process = &mut pixels_to_process.0;
         let mut pixels_to_process_now = BitSet::new();
         let mut count = 0;
         let mut pixel_collection: BitSet;
+        let mut pass = None;
         loop {
             pixel_collection = (&*pixels_to_process)
                 .join()
                 .take_while(|_| { count += 1; count < new_samples_to_process })
                 .collect();
             pixels_to_process_now |= &pixel_collection;
-            (&pixel_positions, &mut pixel_colors, &mut sample_counts, pixels_to_process_now.clone())
+            (&pixel_positions, &mut pixel_colors, &mut sample_counts, &mut second_moments, &mut pixel_aovs, pixels_to_process_now.clone())
                 .par_join()
-                .for_each(|(pixel_position, pixel_color, sample_count, _)| {
+                .for_each(|(pixel_position, pixel_color, sample_count, second_moment, aovs, _)| {
+                    // The pass is reused within a frame, so it may hold pixels that have since
+                    // reached the limit
+                    if !sample_limit.allows_more(sample_count.0) {
+                        return;
+                    }
                     let x = pixel_position.0.x;
-                    let y = height_minus_one - pixel_position.0.y as usize;
-                    let u = (x as f32 + random_float_01()) / width_f32;
-                    let v = (y as f32 + random_float_01()) / height_f32;
-                    let ray = camera.get_ray(u, v);
-                    pixel_color.0 += color(&ray, &positions, &hitables, &materials, 0);
+                    let y = height_minus_one - pixel_position.0.y;
+                    let mut sampler = Sampler::new(*sampler_kind, &blue_noise, seed.0, x, y, sample_count.0 as u32);
+                    let (du, dv) = sampler.next_2d();
+                    let u = (x as f32 + du) / width_f32;
+                    let v = (y as f32 + dv) / height_f32;
+                    let ray = camera.get_ray(u, v, &mut sampler);
+                    let mut sample_aovs = PixelAovs::default();
+                    let radiance = color(&ray, &scene, &mut sampler, &mut sample_aovs);
+                    aovs.accumulate(&sample_aovs, sample_count.0 == 0.0);
+                    pixel_color.0 += radiance.into();
                     sample_count.0 += 1.0;
+                    second_moment.0 += luminance(radiance).powi(2);
                 });
             for (
                 pixel_position,
                 pixel_color,
                 sample_count,
+                aovs,
                 _,
             ) in (
                 &pixel_positions,
                 &pixel_colors,
                 &sample_counts,
+                &pixel_aovs,
                 &pixels_to_process_now,
             ).join() {
-                let x = pixel_position.0.x as usize;
-                let y = pixel_position.0.y as usize;
+                let x = pixel_position.0.x;
+                let y = pixel_position.0.y;
                 let i = y * width + x;
-                let (a, r, g, b) = (pixel_color.0 / sample_count.0).as_argb8888();
-                buffer[i * 4 + 0] = r;
-                buffer[i * 4 + 1] = g;
-                buffer[i * 4 + 2] = b;
-                buffer[i * 4 + 3] = a;
+                let rgba = display_pixel(&display, display.view, pixel_color, sample_count, aovs, blue_noise.value(x, y));
+                buffer[i * 4..i * 4 + 4].copy_from_slice(&rgba);
             }
             if count >= new_samples_to_process {
                 break;
             }
-            *pixels_to_process = pixel_positions.mask().clone();
-            *pixels_to_process &= pixel_colors.mask();
+            // The error estimates only change between frames, so one selection serves every pass
+            *pixels_to_process = pass.get_or_insert_with(|| next_pass(
+                &adaptive,
+                &sample_limit,
+                new_samples_to_process as usize,
+                &pixel_positions,
+                &sample_counts,
+                &pixel_errors,
+            )).clone();
+            if pixels_to_process.is_empty() {
+                // Every pixel has converged. Keep the budget from growing while idle.
+                *samples_to_process = previous_samples_to_process.min(new_samples_to_process);
+                break;
+            }
             pixels_to_process_now.clear();
         }
         *pixels_to_process &= &!pixels_to_process_now.clone();
 
+        self.last_duration = Some(start.elapsed().as_secs_f64());
         timers.exit("SYSTEM : PathTrace");
     }
 }
 
+/// The relative standard error of a pixel's mean luminance, and the variance of that mean
+pub fn estimate_pixel_error(color: &PixelColor, sample_count: &SampleCount, second_moment: &PixelSecondMoment) -> (f32, f32) {
+    let n = sample_count.0;
+    if n < 2.0 {
+        return (f32::INFINITY, f32::INFINITY);
+    }
+    let mean = color.0.luminance() / n;
+    let sample_variance = ((second_moment.0 - n * mean * mean) / (n - 1.0)).max(0.0);
+    let variance = sample_variance / n;
+    // Offset the mean so that the error of near-black pixels does not blow up
+    (variance.sqrt() / (mean + 0.01), variance)
+}
+
+/// Updates each pixel's `PixelError` and the image-wide `Convergence` estimates
+pub struct MeasureConvergence;
+
+impl<'a> System<'a> for MeasureConvergence {
+    type SystemData = (
+        ReadStorage<'a, PixelColor>,
+        ReadStorage<'a, SampleCount>,
+        ReadStorage<'a, PixelSecondMoment>,
+        WriteStorage<'a, PixelError>,
+        Read<'a, AdaptiveSampling>,
+        Read<'a, SampleLimit>,
+        Write<'a, Convergence>,
+        Write<'a, PerfTimers>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            pixel_colors,
+            sample_counts,
+            second_moments,
+            mut pixel_errors,
+            adaptive,
+            sample_limit,
+            mut convergence,
+            mut timers,
+        ): Self::SystemData
+    ) {
+        use rayon::prelude::*;
+
+        let timers = &mut timers.0;
+        timers.enter("SYSTEM : MeasureConvergence");
+        let (count, active, relative_error, variance) = (&pixel_colors, &sample_counts, &second_moments, &mut pixel_errors)
+            .par_join()
+            .map(|(color, sample_count, second_moment, pixel_error)| {
+                let (relative_error, variance) = estimate_pixel_error(color, sample_count, second_moment);
+                pixel_error.0 = relative_error;
+                let active = (sample_limit.allows_more(sample_count.0)
+                    && adaptive.is_active(sample_count.0, relative_error)) as usize;
+                (1usize, active, relative_error as f64, variance as f64)
+            })
+            .reduce(|| (0, 0, 0.0, 0.0), |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2, a.3 + b.3));
+        if count > 0 {
+            convergence.relative_error = (relative_error / count as f64) as f32;
+            convergence.variance = (variance / count as f64) as f32;
+        }
+        convergence.active_pixels = active;
+        timers.exit("SYSTEM : MeasureConvergence");
+    }
+}
+
+/// The storages that together describe the image
+pub type PixelData<'a> = (
+    ReadStorage<'a, PixelPosition>,
+    ReadStorage<'a, PixelColor>,
+    ReadStorage<'a, SampleCount>,
+    ReadStorage<'a, PixelAovs>,
+);
+
+/// The mean value of a pixel in a view. Depth and object id are replicated to all three channels,
+/// with -1 as the object id of the background.
+pub fn pixel_value(view: View, color: &PixelColor, sample_count: &SampleCount, aovs: &PixelAovs) -> Vec3A {
+    let n = sample_count.0.max(1.0);
+    match view {
+        View::Beauty => color.0.rgb() / n,
+        View::Albedo => aovs.albedo / n,
+        View::Normal => aovs.normal / n,
+        View::Depth => Vec3A::splat(aovs.depth / n),
+        View::ObjectId if aovs.object_id == NO_OBJECT => Vec3A::splat(-1.0),
+        View::ObjectId => Vec3A::splat(aovs.object_id as f32),
+    }
+}
+
+/// The display color of a pixel in a view. `noise` is in [0, 1) and used for dithering.
+pub fn display_pixel(
+    display: &DisplaySettings,
+    view: View,
+    color: &PixelColor,
+    sample_count: &SampleCount,
+    aovs: &PixelAovs,
+    noise: f32,
+) -> [u8; 4] {
+    let value = pixel_value(view, color, sample_count, aovs);
+    let rgba = |c: Vec3A| [quantize(c.x(), 0.0), quantize(c.y(), 0.0), quantize(c.z(), 0.0), 255];
+    match view {
+        View::Beauty => display.to_rgba8(value, noise),
+        View::Albedo => rgba(Vec3A::new(srgb_oetf(value.x()), srgb_oetf(value.y()), srgb_oetf(value.z()))),
+        View::Normal => rgba(0.5 * value + Vec3A::splat(0.5)),
+        View::Depth => {
+            // Exposure scales the depth so that any range of distances can be made visible
+            let d = value.x() * display.exposure.exp2() / 10.0;
+            rgba(Vec3A::splat(d / (1.0 + d)))
+        },
+        View::ObjectId if aovs.object_id == NO_OBJECT => [0, 0, 0, 255],
+        View::ObjectId => {
+            // Offset so that id 0 is not black like the background
+            let hash = (aovs.object_id + 1).wrapping_mul(0x9e37_79b9).rotate_left(13).wrapping_mul(0x85eb_ca6b);
+            [(hash >> 24) as u8, (hash >> 16) as u8, (hash >> 8) as u8, 255]
+        },
+    }
+}
+
+/// Redraws the whole display image when the display settings change, as `PathTrace` only draws
+/// the pixels it samples
+#[derive(Default)]
+pub struct RefreshDisplay {
+    last: Option<(DisplaySettings, bool)>,
+}
+
+impl<'a> System<'a> for RefreshDisplay {
+    type SystemData = (
+        PixelData<'a>,
+        Read<'a, Width>,
+        Read<'a, DisplaySettings>,
+        Read<'a, Denoise>,
+        ReadExpect<'a, BlueNoise>,
+        Write<'a, BufferOutput>,
+    );
+
+    fn run(&mut self, (pixels, width, display, denoise, blue_noise, mut buffer_output): Self::SystemData) {
+        // Turning denoising off leaves the denoised image in the buffer
+        let state = (*display, denoise.enabled);
+        if self.last == Some(state) {
+            return;
+        }
+        self.last = Some(state);
+        let width = width.0;
+        let buffer = &mut buffer_output.0;
+        let (ref pixel_positions, ref pixel_colors, ref sample_counts, ref pixel_aovs) = pixels;
+        for (pixel_position, pixel_color, sample_count, aovs) in (pixel_positions, pixel_colors, sample_counts, pixel_aovs).join() {
+            if sample_count.0 > 0.0 {
+                let x = pixel_position.0.x;
+                let y = pixel_position.0.y;
+                let i = y * width + x;
+                let rgba = display_pixel(&display, display.view, pixel_color, sample_count, aovs, blue_noise.value(x, y));
+                buffer[i * 4..i * 4 + 4].copy_from_slice(&rgba);
+            }
+        }
+    }
+}
+
+/// Filters the beauty image with the À-trous denoiser when it is enabled, and shows the result
+pub struct DenoiseImage;
+
+impl<'a> System<'a> for DenoiseImage {
+    type SystemData = (
+        PixelData<'a>,
+        ReadStorage<'a, PixelSecondMoment>,
+        Read<'a, Width>,
+        Read<'a, Height>,
+        Read<'a, Denoise>,
+        Read<'a, DisplaySettings>,
+        ReadExpect<'a, BlueNoise>,
+        Write<'a, DenoisedImage>,
+        Write<'a, BufferOutput>,
+        Write<'a, PerfTimers>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            pixels,
+            second_moments,
+            width,
+            height,
+            denoise,
+            display,
+            blue_noise,
+            mut denoised,
+            mut buffer_output,
+            mut timers,
+        ): Self::SystemData
+    ) {
+        if !denoise.enabled {
+            denoised.0.clear();
+            return;
+        }
+
+        let timers = &mut timers.0;
+        timers.enter("SYSTEM : DenoiseImage");
+
+        let width = width.0;
+        let height = height.0;
+        let (ref pixel_positions, ref pixel_colors, ref sample_counts, ref pixel_aovs) = pixels;
+        let mut features = vec![
+            PixelFeatures {
+                color: Vec3A::zero(),
+                variance: 0.0,
+                albedo: Vec3A::zero(),
+                normal: Vec3A::zero(),
+                depth: 0.0,
+                sample_count: 0.0,
+            };
+            width * height
+        ];
+        for (pixel_position, pixel_color, sample_count, aovs, second_moment) in
+            (pixel_positions, pixel_colors, sample_counts, pixel_aovs, &second_moments).join()
+        {
+            let n = sample_count.0.max(1.0);
+            features[pixel_position.0.y * width + pixel_position.0.x] = PixelFeatures {
+                color: pixel_color.0.rgb() / n,
+                variance: estimate_pixel_error(pixel_color, sample_count, second_moment).1,
+                albedo: aovs.albedo / n,
+                normal: aovs.normal / n,
+                depth: aovs.depth / n,
+                sample_count: sample_count.0,
+            };
+        }
+        denoised.0 = denoise_image(&denoise, width, height, &features);
+
+        if display.view == View::Beauty {
+            draw_denoised(&display, &blue_noise, width, &denoised.0, &mut buffer_output.0);
+        }
+        timers.exit("SYSTEM : DenoiseImage");
+    }
+}
+
+pub fn save_image(filename: &str, width: usize, height: usize, buffer: &[u8]) -> ImageResult<()> {
+    save_buffer(filename, buffer, width as u32, height as u32, Rgba8)
+}
+
+/// The mean values of every pixel in a view, row by row from the top
+pub fn linear_pixels(view: View, width: usize, height: usize, pixels: &PixelData) -> Vec<Vec3A> {
+    let (ref pixel_positions, ref pixel_colors, ref sample_counts, ref pixel_aovs) = *pixels;
+    let mut values = vec![Vec3A::zero(); width * height];
+    for (pixel_position, pixel_color, sample_count, aovs) in (pixel_positions, pixel_colors, sample_counts, pixel_aovs).join() {
+        values[pixel_position.0.y * width + pixel_position.0.x] = pixel_value(view, pixel_color, sample_count, aovs);
+    }
+    values
+}
+
+/// The display image of a view as 8-bit RGBA, row by row from the top
+pub fn display_image(
+    view: View,
+    display: &DisplaySettings,
+    blue_noise: &BlueNoise,
+    width: usize,
+    height: usize,
+    pixels: &PixelData,
+) -> Vec<u8> {
+    let (ref pixel_positions, ref pixel_colors, ref sample_counts, ref pixel_aovs) = *pixels;
+    let mut buffer = vec![0; width * height * 4];
+    for (pixel_position, pixel_color, sample_count, aovs) in (pixel_positions, pixel_colors, sample_counts, pixel_aovs).join() {
+        let x = pixel_position.0.x;
+        let y = pixel_position.0.y;
+        let i = y * width + x;
+        let rgba = display_pixel(display, view, pixel_color, sample_count, aovs, blue_noise.value(x, y));
+        buffer[i * 4..i * 4 + 4].copy_from_slice(&rgba);
+    }
+    buffer
+}
+
+/// Draw a denoised beauty image, row by row from the top, into a display buffer
+pub fn draw_denoised(display: &DisplaySettings, blue_noise: &BlueNoise, width: usize, denoised: &[Vec3A], buffer: &mut [u8]) {
+    for (i, (&color, rgba)) in denoised.iter().zip(buffer.chunks_mut(4)).enumerate() {
+        let (x, y) = (i % width, i / width);
+        rgba.copy_from_slice(&display.to_rgba8(color, blue_noise.value(x, y)));
+    }
+}
+
+/// Save the beauty image as `{stem}.{extension}` and, if `aovs` is set, every other view next to
+/// it as `{stem}.{view}.{extension}`. PNGs hold display images, the other formats mean values.
+/// The beauty image is the denoised one unless `denoised` is empty. Returns the names of the
+/// files written.
+#[allow(clippy::too_many_arguments)]
+pub fn save_views(
+    stem: &str,
+    format: ImageFormat,
+    aovs: bool,
+    display: &DisplaySettings,
+    blue_noise: &BlueNoise,
+    width: usize,
+    height: usize,
+    pixels: &PixelData,
+    denoised: &[Vec3A],
+) -> Result<Vec<String>, Error> {
+    let views: &[View] = if aovs { &View::ALL } else { &[View::Beauty] };
+    let mut filenames = Vec::new();
+    for &view in views {
+        let filename = if view == View::Beauty {
+            format!("{}.{}", stem, format.extension())
+        } else {
+            format!("{}.{}.{}", stem, view.name(), format.extension())
+        };
+        if view == View::Beauty && !denoised.is_empty() {
+            if format.is_linear() {
+                save_linear_image(&filename, format, width, height, denoised)?;
+            } else {
+                let mut buffer = vec![0; width * height * 4];
+                draw_denoised(display, blue_noise, width, denoised, &mut buffer);
+                save_image(&filename, width, height, &buffer)?;
+            }
+        } else if format.is_linear() {
+            save_linear_image(&filename, format, width, height, &linear_pixels(view, width, height, pixels))?;
+        } else {
+            save_image(&filename, width, height, &display_image(view, display, blue_noise, width, height, pixels))?;
+        }
+        filenames.push(filename);
+    }
+    Ok(filenames)
+}
+
 pub struct SaveImage;
 
 impl<'a> System<'a> for SaveImage {
     type SystemData = (
         Read<'a, ImageFilePrefix>,
+        Read<'a, ImageFileFormat>,
+        Read<'a, SaveAovs>,
         Read<'a, Width>,
         Read<'a, Height>,
         Read<'a, Samples>,
-        Read<'a, BufferOutput>,
+        Read<'a, DisplaySettings>,
+        ReadExpect<'a, BlueNoise>,
+        PixelData<'a>,
+        Read<'a, DenoisedImage>,
         Write<'a, FrameCount>,
         Write<'a, PerfTimers>,
     );
@@ -187,10 +1135,15 @@ impl<'a> System<'a> for SaveImage {
         &mut self,
         (
             prefix,
+            format,
+            save_aovs,
             width,
             height,
             samples,
-            buffer_output,
+            display,
+            blue_noise,
+            pixels,
+            denoised,
             mut frame_count,
             mut timers,
         ): Self::SystemData
@@ -208,9 +1161,8 @@ impl<'a> System<'a> for SaveImage {
 
         let width = width.0;
         let height = height.0;
-        let buffer = &buffer_output.0;
-        let filename = format!("{}{:05}.png", prefix, frame_count);
-        save_buffer(filename, buffer, width as u32, height as u32, Rgba8).unwrap();
+        let stem = format!("{}{:05}", prefix, frame_count);
+        save_views(&stem, format.0, save_aovs.0, &display, &blue_noise, width, height, &pixels, &denoised.0).unwrap();
         timers.exit("SYSTEM : SaveImage");
     }
 }
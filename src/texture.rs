@@ -0,0 +1,195 @@
+use failure::Error;
+use glam::{Vec2, Vec3A};
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+
+use color::srgb_eotf;
+
+const PERLIN_POINTS: usize = 256;
+pub const TURBULENCE_DEPTH: u32 = 7;
+
+/// Where a material's colour comes from. Textures other than `Solid` vary over the surface,
+/// either with the hit point in space or with the surface's texture coordinates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Texture {
+    Solid(Vec3A),
+    // A 3D checkerboard of cubes with sides of length `scale`
+    Checker { even: Vec3A, odd: Vec3A, scale: f32 },
+    // Smooth Perlin noise of features about 1 / `scale` across
+    Noise { color: Vec3A, scale: f32 },
+    // Several octaves of Perlin noise summed
+    Turbulence { color: Vec3A, scale: f32 },
+    // Stripes along z, phase shifted by turbulence
+    Marble { color: Vec3A, scale: f32 },
+    // An index into `Textures::images`, mapped by texture coordinates
+    Image(usize),
+}
+
+impl From<Vec3A> for Texture {
+    fn from(color: Vec3A) -> Texture {
+        Texture::Solid(color)
+    }
+}
+
+/// An image in linear RGB, rows from the top
+#[derive(Debug)]
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3A>,
+}
+
+impl ImageTexture {
+    /// Load an image file. 8-bit images are taken to be sRGB encoded.
+    pub fn load(path: &str) -> Result<ImageTexture, Error> {
+        let image = image::open(path)
+            .map_err(|e| format_err!("failed to load texture {}: {}", path, e))?
+            .to_rgb();
+        let (width, height) = image.dimensions();
+        let pixels = image.pixels()
+            .map(|p| Vec3A::new(
+                srgb_eotf(p[0] as f32 / 255.0),
+                srgb_eotf(p[1] as f32 / 255.0),
+                srgb_eotf(p[2] as f32 / 255.0),
+            ))
+            .collect();
+        Ok(ImageTexture { width: width as usize, height: height as usize, pixels })
+    }
+
+    fn texel(&self, x: isize, y: isize) -> Vec3A {
+        let x = x.rem_euclid(self.width as isize) as usize;
+        let y = y.rem_euclid(self.height as isize) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    /// Bilinearly filtered, wrapping around at the edges. v = 0 is the bottom of the image.
+    pub fn value(&self, uv: Vec2) -> Vec3A {
+        let x = uv.x() * self.width as f32 - 0.5;
+        let y = (1.0 - uv.y()) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+        let top = (1.0 - fx) * self.texel(x0, y0) + fx * self.texel(x0 + 1, y0);
+        let bottom = (1.0 - fx) * self.texel(x0, y0 + 1) + fx * self.texel(x0 + 1, y0 + 1);
+        (1.0 - fy) * top + fy * bottom
+    }
+}
+
+/// Ken Perlin's gradient noise, with random unit gradients on a lattice hashed by permutations
+#[derive(Debug)]
+pub struct Perlin {
+    gradients: Vec<Vec3A>,
+    permutations: [Vec<usize>; 3],
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Perlin {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let gradients = (0..PERLIN_POINTS)
+            .map(|_| {
+                loop {
+                    let v = Vec3A::new(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0));
+                    let length_squared = v.length_squared();
+                    if length_squared > 1e-4 && length_squared <= 1.0 {
+                        return v / length_squared.sqrt();
+                    }
+                }
+            })
+            .collect();
+        let mut permutation = || {
+            let mut p: Vec<usize> = (0..PERLIN_POINTS).collect();
+            for i in (1..PERLIN_POINTS).rev() {
+                p.swap(i, rng.gen_range(0, i + 1));
+            }
+            p
+        };
+        let permutations = [permutation(), permutation(), permutation()];
+        Perlin { gradients, permutations }
+    }
+
+    /// Noise in about [-1, 1]
+    pub fn noise(&self, p: Vec3A) -> f32 {
+        let floor = p.floor();
+        let f = p - floor;
+        // Hermite smoothing of the interpolation weights
+        let w = f * f * (Vec3A::splat(3.0) - 2.0 * f);
+        let mask = PERLIN_POINTS as i32 - 1;
+        let (i, j, k) = (floor.x() as i32, floor.y() as i32, floor.z() as i32);
+        let mut sum = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let hash = self.permutations[0][((i + di) & mask) as usize]
+                        ^ self.permutations[1][((j + dj) & mask) as usize]
+                        ^ self.permutations[2][((k + dk) & mask) as usize];
+                    let corner = Vec3A::new(di as f32, dj as f32, dk as f32);
+                    let weight = corner * w + (Vec3A::one() - corner) * (Vec3A::one() - w);
+                    sum += weight.x() * weight.y() * weight.z() * self.gradients[hash].dot(f - corner);
+                }
+            }
+        }
+        sum
+    }
+
+    /// The sum of the absolute value of octaves of noise, each of twice the frequency and half the
+    /// amplitude of the last
+    pub fn turbulence(&self, p: Vec3A, depth: u32) -> f32 {
+        let mut sum = 0.0;
+        let mut p = p;
+        let mut weight = 1.0;
+        for _ in 0..depth {
+            sum += weight * self.noise(p);
+            weight *= 0.5;
+            p *= 2.0;
+        }
+        sum.abs()
+    }
+}
+
+/// The data that textures refer to
+#[derive(Debug)]
+pub struct Textures {
+    pub images: Vec<ImageTexture>,
+    pub perlin: Perlin,
+}
+
+impl Default for Textures {
+    fn default() -> Textures {
+        Textures {
+            images: Vec::new(),
+            perlin: Perlin::new(0),
+        }
+    }
+}
+
+impl Textures {
+    /// Load an image file and return a texture referring to it
+    pub fn load_image(&mut self, path: &str) -> Result<Texture, Error> {
+        self.images.push(ImageTexture::load(path)?);
+        Ok(Texture::Image(self.images.len() - 1))
+    }
+
+    /// The colour of a texture at a surface point `p` with texture coordinates `uv`
+    pub fn value(&self, texture: &Texture, uv: Vec2, p: Vec3A) -> Vec3A {
+        match *texture {
+            Texture::Solid(color) => color,
+            Texture::Checker { even, odd, scale } => {
+                let cell = (p / scale).floor();
+                if (cell.x() + cell.y() + cell.z()) as i64 % 2 == 0 { even } else { odd }
+            },
+            Texture::Noise { color, scale } => color * 0.5 * (1.0 + self.perlin.noise(scale * p)),
+            Texture::Turbulence { color, scale } => {
+                color * self.perlin.turbulence(scale * p, TURBULENCE_DEPTH).min(1.0)
+            },
+            Texture::Marble { color, scale } => {
+                let phase = scale * p.z() + 10.0 * self.perlin.turbulence(p, TURBULENCE_DEPTH);
+                color * 0.5 * (1.0 + phase.sin())
+            },
+            Texture::Image(i) => match self.images.get(i) {
+                Some(image) => image.value(uv),
+                // A missing image shows up as magenta
+                None => Vec3A::new(1.0, 0.0, 1.0),
+            },
+        }
+    }
+}
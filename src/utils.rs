@@ -1,69 +1,60 @@
-use rand::{Rng, RngCore, SeedableRng, CryptoRng, Error};
-use rand::rngs::SmallRng;
 use glam::Vec3A;
 
-use std::cell::UnsafeCell;
-
-thread_local! {
-    static SMALLRNG: UnsafeCell<SmallRng> = UnsafeCell::new(SmallRng::from_entropy());
-}
-
-pub struct ThreadSmallRng {
-    rng: *mut SmallRng,
-}
-
-pub fn thread_small_rng() -> ThreadSmallRng {
-    ThreadSmallRng { rng: SMALLRNG.with(|t| t.get()) }
-}
-
-impl RngCore for ThreadSmallRng {
-    #[inline(always)]
-    fn next_u32(&mut self) -> u32 {
-        unsafe { (*self.rng).next_u32() }
-    }
-
-    #[inline(always)]
-    fn next_u64(&mut self) -> u64 {
-        unsafe { (*self.rng).next_u64() }
-    }
-
-    fn fill_bytes(&mut self, dest: &mut [u8]) {
-        unsafe { (*self.rng).fill_bytes(dest) }
-    }
-
-    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
-        unsafe { (*self.rng).try_fill_bytes(dest) }
-    }
-}
-
-impl CryptoRng for ThreadSmallRng {}
-
-pub fn random_float_01() -> f32 {
-    thread_small_rng().gen()
-}
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_4, PI};
 
 pub fn lerp_vec3(a: Vec3A, t: f32, b: Vec3A) -> Vec3A {
     (1.0 - t) * a + t * b
 }
 
-pub fn random_in_unit_disk() -> Vec3A {
-    let mut p;
-    let one_one_zero = Vec3A::new(1.0f32, 1.0f32, 0.0f32);
-    // the below is a do {} while () loop
-    while {
-        p = 2.0 * Vec3A::new(random_float_01(), random_float_01(), 0.0) - one_one_zero;
-        p.length_squared() >= 1.0
-    } {}
-    p
-}
-
-pub fn random_in_unit_sphere() -> Vec3A {
-    let mut p;
-    let ones = Vec3A::new(1.0f32, 1.0f32, 1.0f32);
-    // the below is a do {} while () loop
-    while {
-        p = 2.0 * Vec3A::new(random_float_01(), random_float_01(), random_float_01()) - ones;
-        p.length_squared() >= 1.0
-    } {}
-    p
+/// A uniformly distributed point in the unit disk in the xy plane, from a point in [0, 1)^2 using
+/// Shirley and Chiu's concentric mapping, which keeps stratified samples stratified
+pub fn sample_unit_disk(u: (f32, f32)) -> Vec3A {
+    let a = 2.0 * u.0 - 1.0;
+    let b = 2.0 * u.1 - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return Vec3A::zero();
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, FRAC_PI_4 * (b / a))
+    } else {
+        (b, FRAC_PI_2 - FRAC_PI_4 * (a / b))
+    };
+    Vec3A::new(r * theta.cos(), r * theta.sin(), 0.0)
+}
+
+/// A uniformly distributed unit vector from a point in [0, 1)^2
+pub fn sample_unit_vector(u: (f32, f32)) -> Vec3A {
+    let z = 1.0 - 2.0 * u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    Vec3A::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// A uniformly distributed point in the unit ball from a point in [0, 1)^3
+pub fn sample_unit_ball(u: (f32, f32), w: f32) -> Vec3A {
+    w.cbrt() * sample_unit_vector(u)
+}
+
+/// Two unit vectors that together with the unit vector `w` form an orthonormal basis
+pub fn orthonormal_basis(w: Vec3A) -> (Vec3A, Vec3A) {
+    // Duff et al., Building an Orthonormal Basis, Revisited
+    let sign = 1.0f32.copysign(w.z());
+    let a = -1.0 / (sign + w.z());
+    let b = w.x() * w.y() * a;
+    (
+        Vec3A::new(1.0 + sign * w.x() * w.x() * a, sign * b, -sign * w.x()),
+        Vec3A::new(b, sign + w.y() * w.y() * a, -w.y()),
+    )
+}
+
+/// Multiple importance sampling weight for a sample from a strategy with pdf `a` when another
+/// strategy with pdf `b` could also have produced it
+pub fn power_heuristic(a: f32, b: f32) -> f32 {
+    let a2 = a * a;
+    let b2 = b * b;
+    if a2 + b2 > 0.0 {
+        a2 / (a2 + b2)
+    } else {
+        0.0
+    }
 }
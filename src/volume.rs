@@ -0,0 +1,160 @@
+use failure::Error;
+use glam::Vec3A;
+
+use std::fs;
+use std::sync::Arc;
+
+use aabb::Aabb;
+use texture::{Perlin, TURBULENCE_DEPTH};
+
+/// Densities sampled on a regular grid of voxels spanning `bounds`, x varying fastest. Values are
+/// at the centres of the voxels and trilinearly interpolated between them.
+#[derive(Debug)]
+pub struct VoxelGrid {
+    resolution: [usize; 3],
+    bounds: Aabb,
+    values: Vec<f32>,
+    // The largest value, which bounds the interpolated density everywhere
+    max: f32,
+}
+
+// Little-endian reads of the fields of a binary header
+fn read_i32(bytes: &[u8], offset: usize) -> Option<i32> {
+    let field = bytes.get(offset..offset + 4)?;
+    Some(i32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+}
+
+fn read_f32(bytes: &[u8], offset: usize) -> Option<f32> {
+    read_i32(bytes, offset).map(|bits| f32::from_bits(bits as u32))
+}
+
+// The number of voxels in a grid of the given resolution, or None if it overflows
+fn voxel_count(resolution: [usize; 3]) -> Option<usize> {
+    resolution[0].checked_mul(resolution[1])?.checked_mul(resolution[2])
+}
+
+impl VoxelGrid {
+    pub fn new(resolution: [usize; 3], bounds: Aabb, values: Vec<f32>) -> VoxelGrid {
+        assert_eq!(values.len(), resolution[0] * resolution[1] * resolution[2]);
+        let max = values.iter().cloned().fold(0.0, f32::max);
+        VoxelGrid { resolution, bounds, values, max }
+    }
+
+    /// Load a grid of little-endian 32-bit floats of the given resolution, spanning the unit cube
+    pub fn load_raw(path: &str, resolution: [usize; 3]) -> Result<VoxelGrid, Error> {
+        let bytes = fs::read(path).map_err(|e| format_err!("failed to read volume {}: {}", path, e))?;
+        let too_large = || format_err!("volume {} is too large", path);
+        let count = voxel_count(resolution).ok_or_else(too_large)?;
+        let size = count.checked_mul(4).ok_or_else(too_large)?;
+        if bytes.len() != size {
+            return Err(format_err!("volume {} has {} bytes, expected {}", path, bytes.len(), size));
+        }
+        let values = (0..count).map(|i| read_f32(&bytes, 4 * i).unwrap_or(0.0)).collect();
+        Ok(VoxelGrid::new(resolution, Aabb::new(Vec3A::zero(), Vec3A::one()), values))
+    }
+
+    /// Load a grid volume file in Mitsuba's format: the bytes `VOL` and version 3, the encoding
+    /// (1 for 32-bit floats, 3 for bytes), the x, y and z resolution, the number of channels and
+    /// the bounds, followed by the values. Only the first channel is used.
+    pub fn load_vol(path: &str) -> Result<VoxelGrid, Error> {
+        let bytes = fs::read(path).map_err(|e| format_err!("failed to read volume {}: {}", path, e))?;
+        let invalid = || format_err!("volume {} is not a version 3 grid volume", path);
+        if bytes.get(0..4) != Some(b"VOL\x03") {
+            return Err(invalid());
+        }
+        let header = (1..6).map(|i| read_i32(&bytes, 4 * i)).collect::<Option<Vec<i32>>>().ok_or_else(invalid)?;
+        let bounds = (6..12).map(|i| read_f32(&bytes, 4 * i)).collect::<Option<Vec<f32>>>().ok_or_else(invalid)?;
+        let (encoding, channels) = (header[0], header[4].max(1) as usize);
+        let resolution = [header[1].max(0) as usize, header[2].max(0) as usize, header[3].max(0) as usize];
+        let value_size = match encoding {
+            1 => 4,
+            3 => 1,
+            _ => return Err(format_err!("volume {} has an unsupported encoding {}", path, encoding)),
+        };
+        let too_large = || format_err!("volume {} is too large", path);
+        let count = voxel_count(resolution).ok_or_else(too_large)?;
+        let size = count.checked_mul(channels)
+            .and_then(|values| values.checked_mul(value_size))
+            .ok_or_else(too_large)?;
+        let data = &bytes[48..];
+        if data.len() < size {
+            return Err(format_err!("volume {} has {} bytes of data, expected {}", path, data.len(), size));
+        }
+        let values = if encoding == 1 {
+            (0..count).map(|i| read_f32(data, 4 * i * channels).unwrap_or(0.0)).collect()
+        } else {
+            (0..count).map(|i| f32::from(data[i * channels]) / 255.0).collect()
+        };
+        let bounds = Aabb::new(
+            Vec3A::new(bounds[0], bounds[1], bounds[2]),
+            Vec3A::new(bounds[3], bounds[4], bounds[5]),
+        );
+        Ok(VoxelGrid::new(resolution, bounds, values))
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.bounds
+    }
+
+    pub fn max(&self) -> f32 {
+        self.max
+    }
+
+    fn voxel(&self, x: isize, y: isize, z: isize) -> f32 {
+        let clamp = |i: isize, axis: usize| i.max(0).min(self.resolution[axis] as isize - 1) as usize;
+        let (x, y, z) = (clamp(x, 0), clamp(y, 1), clamp(z, 2));
+        self.values[(z * self.resolution[1] + y) * self.resolution[0] + x]
+    }
+
+    /// The density at `p`, zero outside the bounds
+    pub fn value(&self, p: Vec3A) -> f32 {
+        if self.values.is_empty() || p.cmplt(self.bounds.min).any() || p.cmpgt(self.bounds.max).any() {
+            return 0.0;
+        }
+        let resolution = Vec3A::new(self.resolution[0] as f32, self.resolution[1] as f32, self.resolution[2] as f32);
+        let g = (p - self.bounds.min) / self.bounds.extent() * resolution - Vec3A::splat(0.5);
+        let floor = g.floor();
+        let f = g - floor;
+        let (x, y, z) = (floor.x() as isize, floor.y() as isize, floor.z() as isize);
+        let mut sum = 0.0;
+        for dz in 0..2 {
+            for dy in 0..2 {
+                for dx in 0..2 {
+                    let corner = Vec3A::new(dx as f32, dy as f32, dz as f32);
+                    let weight = corner * f + (Vec3A::one() - corner) * (Vec3A::one() - f);
+                    sum += weight.x() * weight.y() * weight.z() * self.voxel(x + dx, y + dy, z + dz);
+                }
+            }
+        }
+        sum
+    }
+}
+
+/// How the density of a heterogeneous medium varies through the medium's own space, from zero
+/// to at most one times the medium's density, unless a grid holds larger values
+pub enum DensityField {
+    Grid(Arc<VoxelGrid>),
+    // Perlin turbulence of features about 1 / `scale` across
+    Noise { perlin: Arc<Perlin>, scale: f32 },
+}
+
+impl DensityField {
+    pub fn noise(seed: u64, scale: f32) -> DensityField {
+        DensityField::Noise { perlin: Arc::new(Perlin::new(seed)), scale }
+    }
+
+    pub fn value(&self, p: Vec3A) -> f32 {
+        match self {
+            DensityField::Grid(grid) => grid.value(p),
+            DensityField::Noise { perlin, scale } => perlin.turbulence(*scale * p, TURBULENCE_DEPTH).min(1.0),
+        }
+    }
+
+    /// An upper bound on the value anywhere
+    pub fn max(&self) -> f32 {
+        match self {
+            DensityField::Grid(grid) => grid.max(),
+            DensityField::Noise { .. } => 1.0,
+        }
+    }
+}
@@ -0,0 +1,78 @@
+extern crate partyarty;
+extern crate rayon;
+
+use partyarty::*;
+
+use std::sync::Arc;
+
+const WIDTH: usize = 24;
+const HEIGHT: usize = 12;
+const SPP: usize = 4;
+const SEED: u64 = 7;
+
+// Render the random scene headless on a pool of `threads` threads and return the accumulated
+// color of each pixel in raster order
+fn render(threads: usize) -> Vec<[u32; 4]> {
+    let mut world = World::new();
+    register_components(&mut world);
+    let scene = builtin_scene(&mut world, "random", SEED).expect("random is a built-in scene");
+
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            world.create_entity()
+                .with(pixel_position(x, y))
+                .with(pixel_color(0.0, 0.0, 0.0, 0.0))
+                .with(SampleCount(0.0))
+                .with(PixelSecondMoment(0.0))
+                .with(PixelError(f32::INFINITY))
+                .with(PixelAovs::default())
+                .build();
+        }
+    }
+
+    world.insert(scene.camera.camera(WIDTH as f32 / HEIGHT as f32));
+    world.insert(Width(WIDTH));
+    world.insert(Height(HEIGHT));
+    world.insert(SamplesToProcessPerFrame(100));
+    world.insert(TargetFrameDuration(1.0));
+    world.insert(BufferOutput(vec![0; WIDTH * HEIGHT * 4]));
+    world.insert(PixelsToProcess(BitSet::new()));
+    world.insert(scene.render.background.unwrap_or_default());
+    world.insert(BlueNoise::generate(16, SEED));
+    world.insert(SampleLimit(SPP));
+    world.insert(Seed(SEED));
+
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+    let mut dispatcher = DispatcherBuilder::new()
+        .with_pool(Arc::new(pool))
+        .with(BuildBvh::default(), "build_bvh", &[])
+        .with(BuildLights::default(), "build_lights", &[])
+        .with(ClearAccumulation::default(), "clear_accumulation", &[])
+        .with(PathTrace::default(), "path_trace", &["build_bvh", "build_lights", "clear_accumulation"])
+        .build();
+    dispatcher.setup(&mut world);
+
+    loop {
+        let done = world.read_storage::<SampleCount>().join().all(|sample_count| sample_count.0 >= SPP as f32);
+        if done {
+            break;
+        }
+        dispatcher.dispatch(&world);
+        world.maintain();
+    }
+
+    let mut pixels = vec![[0; 4]; WIDTH * HEIGHT];
+    for (position, color) in (&world.read_storage::<PixelPosition>(), &world.read_storage::<PixelColor>()).join() {
+        let c = color.0;
+        pixels[position.0.y * WIDTH + position.0.x] = [c.r.to_bits(), c.g.to_bits(), c.b.to_bits(), c.a.to_bits()];
+    }
+    pixels
+}
+
+#[test]
+fn same_seed_gives_same_image_on_any_number_of_threads() {
+    let single = render(1);
+    assert!(single.iter().any(|c| c[..3].iter().any(|&bits| f32::from_bits(bits) > 0.0)));
+    assert_eq!(single, render(1));
+    assert_eq!(single, render(4));
+}